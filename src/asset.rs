@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+
+use bevy::asset::{Asset, Assets, Handle, HandleId};
+use bevy::ecs::component::Tick;
+use bevy::ecs::system::{Deferred, SystemBuffer, SystemMeta, SystemParam, SystemState};
+use bevy::prelude::{Event, EventReader, Mut, ResMut, World};
+
+use crate::undo_event::UndoScheduler;
+
+/// Sets the asset of the handle to `value`, or removes it if `value` is `None`.
+///
+/// Registered for each asset type via [`AppUndoEx::add_undo_asset`](crate::extension::AppUndoEx::add_undo_asset).
+#[derive(Event, Clone)]
+pub(crate) struct UndoAssetEvent<T: Asset + Clone> {
+    handle: Handle<T>,
+    value: Option<T>,
+}
+
+
+/// Behaves like `ResMut<Assets<T>>`, but the first mutable access to each asset snapshots its value.
+///
+/// When the system's commands are applied, the changes of all accessed assets are registered in the history as one step;
+/// assets that were not written to are left out.
+/// The asset type has to be set up with [`AppUndoEx::add_undo_asset`](crate::extension::AppUndoEx::add_undo_asset).
+#[derive(SystemParam)]
+pub struct UndoAssets<'w, 's, T: Asset + Clone> {
+    assets: ResMut<'w, Assets<T>>,
+    snapshots: Deferred<'s, AssetSnapshots<T>>,
+}
+
+
+impl<'w, 's, T: Asset + Clone> UndoAssets<'w, 's, T> {
+    #[inline]
+    pub fn get(&self, handle: &Handle<T>) -> Option<&T> {
+        self.assets.get(handle)
+    }
+
+
+    #[inline]
+    pub fn get_mut(&mut self, handle: &Handle<T>) -> Option<Mut<'_, T>> {
+        let asset = self.assets.get_mut(handle)?;
+        let snapshot = self
+            .snapshots
+            .0
+            .entry(handle.id())
+            .or_insert_with(|| AssetSnapshot::new(Some(asset.clone()), UNCHANGED));
+        Some(snapshot.track(asset))
+    }
+
+
+    /// Adds the asset; undo removes it again.
+    #[inline]
+    pub fn add(&mut self, asset: T) -> Handle<T> {
+        let handle = self.assets.add(asset);
+        self.snapshots.0.insert(handle.id(), AssetSnapshot::new(None, WRITTEN));
+        handle
+    }
+}
+
+
+const UNCHANGED: Tick = Tick::new(0);
+
+
+const WRITTEN: Tick = Tick::new(1);
+
+
+/// The value of an asset before it was accessed, and the tick telling whether it has been written to since.
+struct AssetSnapshot<T> {
+    before: Option<T>,
+    added: Tick,
+    changed: Tick,
+}
+
+
+impl<T> AssetSnapshot<T> {
+    #[inline]
+    const fn new(before: Option<T>, changed: Tick) -> Self {
+        Self {
+            before,
+            added: UNCHANGED,
+            changed,
+        }
+    }
+
+
+    /// Wraps the asset so that writing to it marks the snapshot as [`WRITTEN`].
+    #[inline]
+    fn track<'a>(&'a mut self, asset: &'a mut T) -> Mut<'a, T> {
+        Mut::new(asset, &mut self.added, &mut self.changed, UNCHANGED, WRITTEN)
+    }
+
+
+    #[inline(always)]
+    fn is_written(&self) -> bool {
+        self.changed == WRITTEN
+    }
+}
+
+
+pub(crate) struct AssetSnapshots<T>(HashMap<HandleId, AssetSnapshot<T>>);
+
+
+impl<T> Default for AssetSnapshots<T> {
+    #[inline(always)]
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+
+impl<T: Asset + Clone> SystemBuffer for AssetSnapshots<T> {
+    fn apply(&mut self, _: &SystemMeta, world: &mut World) {
+        if self.0.is_empty() {
+            return;
+        }
+
+        let mut state = SystemState::<(UndoScheduler<UndoAssetEvent<T>>, ResMut<Assets<T>>)>::new(world);
+        let (mut scheduler, assets) = state.get_mut(world);
+        let entries = self
+            .0
+            .drain()
+            .filter(|(_, snapshot)| snapshot.is_written())
+            .map(|(id, AssetSnapshot { before, .. })| {
+                let handle = Handle::<T>::weak(id);
+                let after = assets.get(&handle).cloned();
+                (UndoAssetEvent { handle: handle.clone(), value: before }, UndoAssetEvent { handle, value: after })
+            });
+        scheduler.register_group_with_redo(entries);
+        state.apply(world);
+    }
+}
+
+
+pub(crate) fn undo_asset_event_system<T: Asset + Clone>(
+    mut er: EventReader<UndoAssetEvent<T>>,
+    mut assets: ResMut<Assets<T>>,
+) {
+    for UndoAssetEvent { handle, value } in er.iter() {
+        match value {
+            Some(value) => assets.set_untracked(handle, value.clone()),
+            None => {
+                assets.remove(handle);
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use std::any::TypeId;
+use std::sync::{Arc, Mutex};
+
+use bevy::app::{App, Plugin, Update};
+use bevy::ecs::event::{Events, ManualEventReader};
+use bevy::ecs::system::{Command, EntityCommands, SystemState};
+use bevy::hierarchy::BuildWorldChildren;
+use bevy::prelude::{Bundle, Commands, Component, Entity, Event, IntoSystemConfigs, Local, World};
+use bevy::reflect::Reflect;
+
+use crate::command::component::ChangeComponent;
+use crate::command::entity::{UndoableDespawn, UndoableSpawn};
+use crate::command::hierarchy::ChangeHierarchy;
+use crate::command::reflect::EditProperty;
+use crate::extension::AppUndoEx;
+use crate::remap::{UndoEntityMap, UndoRemapping};
+use crate::request::request_queue_system;
+use crate::undo_event::{UndoHandle, UndoScheduler};
+
+pub(crate) mod component;
+mod entity;
+mod hierarchy;
+pub(crate) mod query;
+pub(crate) mod reflect;
+pub(crate) mod resource;
+
+/// A reversible operation applied directly to the [`World`].
+///
+/// Register one via [`CommandsUndoEx::add_undoable`]; it is applied immediately and stored in the history,
+/// then [`revert`](UndoableCommand::revert) and [`apply`](UndoableCommand::apply) are called
+/// when the step is undone and redone, so no reader system has to be written.
+pub trait UndoableCommand: Send + 'static {
+    fn apply(&mut self, world: &mut World);
+
+
+    fn revert(&mut self, world: &mut World);
+
+
+    /// Rewrites the entities held by the command after they have been restored with new ids.
+    ///
+    /// This is called before each apply and revert; commands that refer to entities should map them here.
+    #[inline(always)]
+    fn map_entities(&mut self, _map: &UndoEntityMap) {}
+}
+
+
+pub trait CommandsUndoEx<'w, 's> {
+    /// Applies the command and registers it in the history as a new step.
+    fn add_undoable(&mut self, command: impl UndoableCommand);
+
+
+    /// Spawns the entity and registers the spawn in the history as a new step.
+    ///
+    /// Undo despawns the entity and redo spawns it again from its reflected components,
+    /// so only components registered with `#[reflect(Component)]` are restored on redo.
+    fn spawn_undoable<'a>(&'a mut self, bundle: impl Bundle) -> EntityCommands<'w, 's, 'a>;
+}
+
+
+impl<'w, 's> CommandsUndoEx<'w, 's> for Commands<'w, 's> {
+    #[inline]
+    fn add_undoable(&mut self, command: impl UndoableCommand) {
+        self.add(ApplyUndoable(command));
+    }
+
+
+    fn spawn_undoable<'a>(&'a mut self, bundle: impl Bundle) -> EntityCommands<'w, 's, 'a> {
+        let entity = self.spawn(bundle).id();
+        self.add(RegisterUndoable(UndoableSpawn::new(entity)));
+        self.entity(entity)
+    }
+}
+
+
+pub trait EntityCommandsUndoEx {
+    /// Despawns the entity and registers the despawn in the history as a new step.
+    ///
+    /// Undo spawns the entity again from its reflected components, puts it back at the same position
+    /// in its parent's children and attaches its children again.
+    /// Only components registered with `#[reflect(Component)]` are restored,
+    /// and the restored entity gets a new id.
+    fn despawn_undoable(self);
+
+
+    /// Despawns the entity with all its descendants and registers the despawn in the history as a new step.
+    ///
+    /// Undo spawns the whole subtree again, keeping the order of children, and puts it back at the same position
+    /// in its parent's children. As with [`despawn_undoable`](EntityCommandsUndoEx::despawn_undoable),
+    /// only reflected components are restored and the restored entities get new ids.
+    fn despawn_recursive_undoable(self);
+
+
+    /// Sets the parent of the entity and registers the change in the history as a new step.
+    ///
+    /// Undo puts the entity back at the same position in its former parent's children, or removes the parent if it had none.
+    fn set_parent_undoable(&mut self, parent: Entity) -> &mut Self;
+
+
+    /// Removes the parent of the entity and registers the change in the history as a new step.
+    ///
+    /// Undo puts the entity back at the same position in its parent's children.
+    fn remove_parent_undoable(&mut self) -> &mut Self;
+
+
+    /// Pushes the children to the back of the entity's children and registers the change in the history as a new step.
+    ///
+    /// Undo restores the exact order of children of the entity and of the former parents of `children`.
+    fn push_children_undoable(&mut self, children: &[Entity]) -> &mut Self;
+
+
+    /// Inserts the component and registers the change in the history as a new step.
+    ///
+    /// Undo restores the previous value of the component, or removes it if the entity did not have one.
+    fn insert_undoable<C: Component + Clone>(&mut self, component: C) -> &mut Self;
+
+
+    /// Removes the component and registers the change in the history as a new step.
+    ///
+    /// Undo inserts the removed value again. Nothing is registered if the entity does not have the component.
+    fn remove_undoable<C: Component + Clone>(&mut self) -> &mut Self;
+
+
+    /// Modifies the component with `f` and registers the change in the history as a new step.
+    ///
+    /// Undo restores the value from before `f` was called. Nothing is registered if the entity does not have the component.
+    fn modify_undoable<C: Component + Clone>(&mut self, f: impl FnOnce(&mut C) + Send + 'static) -> &mut Self;
+
+
+    /// Sets the field of the reflected component at `path`, such as `"translation.x"`, and registers the edit in the history as a new step.
+    ///
+    /// Undo restores the value the field had before. The component has to be registered with `#[reflect(Component)]`,
+    /// and nothing is registered if the field can not be found.
+    fn set_property_undoable(&mut self, component: TypeId, path: impl Into<String>, value: Box<dyn Reflect>) -> &mut Self;
+}
+
+
+impl<'w, 's, 'a> EntityCommandsUndoEx for EntityCommands<'w, 's, 'a> {
+    #[inline]
+    fn despawn_undoable(mut self) {
+        let entity = self.id();
+        self.commands().add_undoable(UndoableDespawn::new(entity));
+    }
+
+
+    #[inline]
+    fn despawn_recursive_undoable(mut self) {
+        let entity = self.id();
+        self.commands().add_undoable(UndoableDespawn::recursive(entity));
+    }
+
+
+    fn set_parent_undoable(&mut self, parent: Entity) -> &mut Self {
+        let entity = self.id();
+        self.commands().add(ChangeHierarchy::new(vec![parent], vec![entity], move |world: &mut World| {
+            world.entity_mut(entity).set_parent(parent);
+        }));
+        self
+    }
+
+
+    fn remove_parent_undoable(&mut self) -> &mut Self {
+        let entity = self.id();
+        self.commands().add(ChangeHierarchy::new(Vec::new(), vec![entity], move |world: &mut World| {
+            world.entity_mut(entity).remove_parent();
+        }));
+        self
+    }
+
+
+    fn push_children_undoable(&mut self, children: &[Entity]) -> &mut Self {
+        let entity = self.id();
+        let pushed = children.to_vec();
+        self.commands().add(ChangeHierarchy::new(vec![entity], children.to_vec(), move |world: &mut World| {
+            world.entity_mut(entity).push_children(&pushed);
+        }));
+        self
+    }
+
+
+    fn insert_undoable<C: Component + Clone>(&mut self, component: C) -> &mut Self {
+        let entity = self.id();
+        self.commands().add(ChangeComponent::new(entity, move |_: Option<&C>| Some(component)));
+        self
+    }
+
+
+    fn remove_undoable<C: Component + Clone>(&mut self) -> &mut Self {
+        let entity = self.id();
+        self.commands().add(ChangeComponent::new(entity, |_: Option<&C>| None));
+        self
+    }
+
+
+    fn modify_undoable<C: Component + Clone>(&mut self, f: impl FnOnce(&mut C) + Send + 'static) -> &mut Self {
+        let entity = self.id();
+        self.commands().add(ChangeComponent::new(entity, move |component: Option<&C>| {
+            let mut component = component?.clone();
+            f(&mut component);
+            Some(component)
+        }));
+        self
+    }
+
+
+    fn set_property_undoable(&mut self, component: TypeId, path: impl Into<String>, value: Box<dyn Reflect>) -> &mut Self {
+        let entity = self.id();
+        self.commands().add(EditProperty::new(entity, component, path.into(), value));
+        self
+    }
+}
+
+
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default)]
+pub(crate) struct UndoCommandPlugin;
+
+
+impl Plugin for UndoCommandPlugin {
+    #[inline]
+    fn build(&self, app: &mut App) {
+        app
+            .add_undo_event::<UndoCommandEvent>()
+            .add_systems(Update, undo_command_event_system.after(request_queue_system));
+        app.world.resource_mut::<UndoRemapping>().push_restorer(TypeId::of::<UndoCommandEvent>());
+    }
+}
+
+
+#[derive(Event, Clone)]
+pub(crate) struct UndoCommandEvent {
+    command: Arc<Mutex<dyn UndoableCommand>>,
+    revert: bool,
+}
+
+
+struct ApplyUndoable<C>(C);
+
+
+impl<C: UndoableCommand> Command for ApplyUndoable<C> {
+    fn apply(mut self, world: &mut World) {
+        self.0.apply(world);
+        register_command(world, self.0);
+    }
+}
+
+
+/// Registers the command without applying it, because its effect has already been applied.
+struct RegisterUndoable<C>(C);
+
+
+impl<C: UndoableCommand> Command for RegisterUndoable<C> {
+    #[inline]
+    fn apply(self, world: &mut World) {
+        register_command(world, self.0);
+    }
+}
+
+
+/// Registers the command, which has already been applied, in the history.
+pub(crate) fn register_command(world: &mut World, command: impl UndoableCommand) -> UndoHandle {
+    let mut state = SystemState::<UndoScheduler<UndoCommandEvent>>::new(world);
+    let handle = state.get_mut(world).register_command(command);
+    state.apply(world);
+    handle
+}
+
+
+impl<'w> UndoScheduler<'w, UndoCommandEvent> {
+    /// Registers the command, which has already been applied, in the history.
+    #[inline]
+    pub(crate) fn register_command(&mut self, command: impl UndoableCommand) -> UndoHandle {
+        let (undo, redo) = UndoCommandEvent::pair(command);
+        self.register_with_redo(undo, redo)
+    }
+
+
+    /// Registers the commands, which have already been applied, in the history as one step.
+    #[inline]
+    pub(crate) fn register_commands(&mut self, commands: Vec<impl UndoableCommand>) {
+        self.register_group_with_redo(commands.into_iter().map(UndoCommandEvent::pair));
+    }
+}
+
+
+impl UndoCommandEvent {
+    /// Returns the events which revert and apply the command.
+    fn pair(command: impl UndoableCommand) -> (Self, Self) {
+        let command: Arc<Mutex<dyn UndoableCommand>> = Arc::new(Mutex::new(command));
+        (
+            Self { command: command.clone(), revert: true },
+            Self { command, revert: false }
+        )
+    }
+}
+
+
+fn undo_command_event_system(
+    world: &mut World,
+    mut er: Local<ManualEventReader<UndoCommandEvent>>,
+) {
+    let events = er
+        .iter(world.resource::<Events<UndoCommandEvent>>())
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for UndoCommandEvent { command, revert } in events {
+        let Ok(mut command) = command.lock() else { continue; };
+        if let Some(map) = world.get_resource::<UndoEntityMap>() {
+            command.map_entities(map);
+        }
+        if revert {
+            command.revert(world);
+        } else {
+            command.apply(world);
+        }
+    }
+}
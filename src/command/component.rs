@@ -0,0 +1,168 @@
+use std::marker::PhantomData;
+
+use bevy::ecs::system::Command;
+use bevy::ecs::world::EntityMut;
+use bevy::prelude::{Changed, Commands, Component, Entity, Query, Res, Without, World};
+
+use crate::command::{register_command, UndoableCommand, UndoCommandEvent};
+use crate::context::UndoApplying;
+use crate::remap::UndoEntityMap;
+use crate::undo_event::UndoScheduler;
+
+/// Sets the component of the entity to `after` on apply and to `before` on revert;
+/// `None` means the entity does not have the component.
+pub(crate) struct UndoableComponent<C> {
+    entity: Entity,
+    before: Option<C>,
+    after: Option<C>,
+}
+
+
+impl<C: Component + Clone> UndoableComponent<C> {
+    #[inline(always)]
+    pub const fn new(entity: Entity, before: Option<C>, after: Option<C>) -> Self {
+        Self {
+            entity,
+            before,
+            after,
+        }
+    }
+
+
+    /// Sets the component and its [`UndoShadow`], so that the change is not recorded again by a tracking system.
+    fn set(&self, world: &mut World, component: Option<&C>) {
+        let Some(mut entity) = world.get_entity_mut(self.entity) else { return; };
+        match component {
+            Some(component) => {
+                entity.insert(component.clone());
+                UndoShadow::suppress(&mut entity, component);
+            }
+            None => {
+                entity.remove::<(C, UndoShadow<C>)>();
+            }
+        }
+    }
+}
+
+
+impl<C: Component + Clone> UndoableCommand for UndoableComponent<C> {
+    #[inline]
+    fn apply(&mut self, world: &mut World) {
+        self.set(world, self.after.as_ref());
+    }
+
+
+    #[inline]
+    fn revert(&mut self, world: &mut World) {
+        self.set(world, self.before.as_ref());
+    }
+
+
+    #[inline]
+    fn map_entities(&mut self, map: &UndoEntityMap) {
+        map.map(&mut self.entity);
+    }
+}
+
+
+/// Captures the current component of the entity, changes it with `change` and registers the change in the history.
+///
+/// Nothing is registered if the entity does not exist, or it neither has nor gets the component.
+pub(crate) struct ChangeComponent<C, F> {
+    entity: Entity,
+    change: F,
+    _marker: PhantomData<fn() -> C>,
+}
+
+
+impl<C, F> ChangeComponent<C, F>
+    where
+        C: Component + Clone,
+        F: FnOnce(Option<&C>) -> Option<C> + Send + 'static
+{
+    #[inline(always)]
+    pub const fn new(entity: Entity, change: F) -> Self {
+        Self {
+            entity,
+            change,
+            _marker: PhantomData,
+        }
+    }
+}
+
+
+impl<C, F> Command for ChangeComponent<C, F>
+    where
+        C: Component + Clone,
+        F: FnOnce(Option<&C>) -> Option<C> + Send + 'static
+{
+    fn apply(self, world: &mut World) {
+        let Some(entity) = world.get_entity(self.entity) else { return; };
+        let before = entity.get::<C>().cloned();
+        let after = (self.change)(before.as_ref());
+        if before.is_none() && after.is_none() {
+            return;
+        }
+
+        let mut command = UndoableComponent::new(self.entity, before, after);
+        command.apply(world);
+        register_command(world, command);
+    }
+}
+
+
+/// The last committed value of a component tracked via [`AppUndoEx::track_undo_component`](crate::extension::AppUndoEx::track_undo_component).
+#[derive(Component)]
+pub(crate) struct UndoShadow<C: Component + Clone> {
+    value: C,
+
+    /// Set when the component was changed by an undoable command, which has already been registered.
+    suppress: bool,
+}
+
+
+impl<C: Component + Clone> UndoShadow<C> {
+    /// Marks the change of the component, which has been registered already, so that the tracking system does not record it again.
+    pub fn suppress(entity: &mut EntityMut, component: &C) {
+        if let Some(mut shadow) = entity.get_mut::<Self>() {
+            shadow.value = component.clone();
+            shadow.suppress = true;
+        }
+    }
+}
+
+
+/// Registers the changes of the tracked component made since the last run as one step.
+///
+/// While an [`UndoApplying`] is active, the changes are made by the readers of undo and redo events,
+/// so only the shadow copies are updated.
+pub(crate) fn track_component_system<C: Component + Clone>(
+    mut commands: Commands,
+    mut scheduler: UndoScheduler<UndoCommandEvent>,
+    applying: Res<UndoApplying>,
+    untracked: Query<(Entity, &C), Without<UndoShadow<C>>>,
+    mut tracked: Query<(Entity, &C, &mut UndoShadow<C>), Changed<C>>,
+) {
+    for (entity, component) in untracked.iter() {
+        commands.entity(entity).insert(UndoShadow {
+            value: component.clone(),
+            suppress: false,
+        });
+    }
+
+    let changes = tracked
+        .iter_mut()
+        .filter_map(|(entity, component, mut shadow)| {
+            if std::mem::take(&mut shadow.suppress) {
+                return None;
+            }
+            let before = std::mem::replace(&mut shadow.value, component.clone());
+            if applying.is_active() {
+                return None;
+            }
+            Some(UndoableComponent::new(entity, Some(before), Some(component.clone())))
+        })
+        .collect::<Vec<_>>();
+
+    scheduler.register_commands(changes);
+}
@@ -0,0 +1,250 @@
+use bevy::ecs::entity::EntityMap;
+use bevy::hierarchy::{BuildWorldChildren, Children, DespawnRecursiveExt, Parent};
+use bevy::log::warn;
+use bevy::prelude::{Entity, World};
+use bevy::scene::{DynamicScene, DynamicSceneBuilder};
+
+use crate::command::UndoableCommand;
+use crate::remap::UndoEntityMap;
+
+/// The reflected state of a despawned entity, used to spawn it again.
+///
+/// Only components registered in the [`AppTypeRegistry`](bevy::prelude::AppTypeRegistry)
+/// with `#[reflect(Component)]` are kept.
+pub(crate) struct EntitySnapshot {
+    scene: DynamicScene,
+    entity: Entity,
+    parent: Option<(Entity, usize)>,
+    children: Vec<Entity>,
+
+    /// The children of each entity of the subtree, which is empty unless the entity was despawned recursively.
+    descendants: Vec<(Entity, Vec<Entity>)>,
+}
+
+
+impl EntitySnapshot {
+    /// Despawns the entity, detaching it from its parent and children, and returns its snapshot.
+    pub fn despawn(world: &mut World, entity: Entity) -> Option<Self> {
+        world.get_entity(entity)?;
+
+        let scene = extract(world, vec![entity]);
+        let parent = parent_of(world, entity);
+        let children = children_of(world, entity);
+
+        let mut entity_mut = world.entity_mut(entity);
+        entity_mut
+            .remove_parent()
+            .remove_children(&children);
+        entity_mut.despawn();
+
+        Some(Self {
+            scene,
+            entity,
+            parent,
+            children,
+            descendants: Vec::new(),
+        })
+    }
+
+
+    /// Despawns the entity with all its descendants, detaching it from its parent, and returns the snapshot of the subtree.
+    pub fn despawn_recursive(world: &mut World, entity: Entity) -> Option<Self> {
+        world.get_entity(entity)?;
+
+        let mut descendants = vec![(entity, children_of(world, entity))];
+        let mut i = 0;
+        while let Some((_, children)) = descendants.get(i) {
+            let children = children
+                .iter()
+                .map(|child| (*child, children_of(world, *child)))
+                .collect::<Vec<_>>();
+            descendants.extend(children);
+            i += 1;
+        }
+
+        let scene = extract(world, descendants.iter().map(|(entity, _)| *entity).collect());
+        let parent = parent_of(world, entity);
+
+        let mut entity_mut = world.entity_mut(entity);
+        entity_mut.remove_parent();
+        entity_mut.despawn_recursive();
+
+        Some(Self {
+            scene,
+            entity,
+            parent,
+            children: Vec::new(),
+            descendants,
+        })
+    }
+
+
+    fn map_entities(&mut self, map: &UndoEntityMap) {
+        if let Some((parent, _)) = &mut self.parent {
+            map.map(parent);
+        }
+        self.children.iter_mut().for_each(|child| map.map(child));
+    }
+
+
+    /// Spawns the entity again and returns its new id.
+    ///
+    /// The entity is put back at the same position in its parent's children,
+    /// and the children that have not been given another parent meanwhile are attached to it again.
+    /// A despawned subtree is spawned again as a whole, keeping the order of children.
+    pub fn spawn(&self, world: &mut World) -> Entity {
+        let mut entity_map = EntityMap::default();
+        if let Err(e) = self.scene.write_to_world(world, &mut entity_map) {
+            warn!("failed to restore the despawned entity {:?}: {e}", self.entity);
+        }
+        let entity = entity_map
+            .get(self.entity)
+            .unwrap_or_else(|| world.spawn_empty().id());
+        entity_map.insert(self.entity, entity);
+        UndoEntityMap::record(world, &entity_map);
+
+        for (parent, children) in &self.descendants {
+            let children = children
+                .iter()
+                .filter_map(|child| entity_map.get(*child))
+                .collect::<Vec<_>>();
+            if let (Some(parent), false) = (entity_map.get(*parent), children.is_empty()) {
+                world.entity_mut(parent).push_children(&children);
+            }
+        }
+
+        if let Some((parent, index)) = self.parent.filter(|(parent, _)| world.get_entity(*parent).is_some()) {
+            let len = world.get::<Children>(parent).map(|children| children.len()).unwrap_or_default();
+            world.entity_mut(parent).insert_children(index.min(len), &[entity]);
+        }
+
+        let children = self
+            .children
+            .iter()
+            .copied()
+            .filter(|child| world.get_entity(*child).is_some_and(|child| !child.contains::<Parent>()))
+            .collect::<Vec<_>>();
+        world.entity_mut(entity).push_children(&children);
+        entity
+    }
+}
+
+
+fn extract(world: &World, entities: Vec<Entity>) -> DynamicScene {
+    let mut builder = DynamicSceneBuilder::from_world(world);
+    builder
+        .deny::<Parent>()
+        .deny::<Children>()
+        .extract_entities(entities.into_iter());
+    builder.build()
+}
+
+
+/// Returns the parent of the entity and the index of the entity in its children.
+fn parent_of(world: &World, entity: Entity) -> Option<(Entity, usize)> {
+    let parent = world.get::<Parent>(entity)?.get();
+    let index = children_of(world, parent)
+        .iter()
+        .position(|child| *child == entity)
+        .unwrap_or_default();
+    Some((parent, index))
+}
+
+
+#[inline]
+fn children_of(world: &World, entity: Entity) -> Vec<Entity> {
+    world
+        .get::<Children>(entity)
+        .map(|children| children.to_vec())
+        .unwrap_or_default()
+}
+
+
+/// Despawns the spawned entity on undo and spawns it again on redo.
+pub(crate) struct UndoableSpawn {
+    entity: Entity,
+    despawned: Option<EntitySnapshot>,
+    recursive: bool,
+}
+
+
+impl UndoableSpawn {
+    #[inline(always)]
+    pub const fn new(entity: Entity) -> Self {
+        Self {
+            entity,
+            despawned: None,
+            recursive: false,
+        }
+    }
+}
+
+
+impl UndoableCommand for UndoableSpawn {
+    fn apply(&mut self, world: &mut World) {
+        if let Some(snapshot) = self.despawned.take() {
+            self.entity = snapshot.spawn(world);
+        }
+    }
+
+
+    fn map_entities(&mut self, map: &UndoEntityMap) {
+        map.map(&mut self.entity);
+        if let Some(snapshot) = &mut self.despawned {
+            snapshot.map_entities(map);
+        }
+    }
+
+
+    fn revert(&mut self, world: &mut World) {
+        self.despawned = if self.recursive {
+            EntitySnapshot::despawn_recursive(world, self.entity)
+        } else {
+            EntitySnapshot::despawn(world, self.entity)
+        };
+    }
+}
+
+
+/// Despawns the entity and spawns it again on undo.
+#[repr(transparent)]
+pub(crate) struct UndoableDespawn(UndoableSpawn);
+
+
+impl UndoableDespawn {
+    #[inline(always)]
+    pub const fn new(entity: Entity) -> Self {
+        Self(UndoableSpawn::new(entity))
+    }
+
+
+    /// Despawns the entity with all its descendants, and spawns the whole subtree again on undo.
+    #[inline(always)]
+    pub const fn recursive(entity: Entity) -> Self {
+        Self(UndoableSpawn {
+            entity,
+            despawned: None,
+            recursive: true,
+        })
+    }
+}
+
+
+impl UndoableCommand for UndoableDespawn {
+    #[inline(always)]
+    fn apply(&mut self, world: &mut World) {
+        self.0.revert(world);
+    }
+
+
+    #[inline(always)]
+    fn revert(&mut self, world: &mut World) {
+        self.0.apply(world);
+    }
+
+
+    #[inline(always)]
+    fn map_entities(&mut self, map: &UndoEntityMap) {
+        self.0.map_entities(map);
+    }
+}
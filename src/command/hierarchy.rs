@@ -0,0 +1,147 @@
+use bevy::ecs::system::Command;
+use bevy::hierarchy::{BuildWorldChildren, Children, Parent};
+use bevy::prelude::{Entity, World};
+
+use crate::command::{register_command, UndoableCommand};
+use crate::remap::UndoEntityMap;
+
+/// The parents and the exact order of children of the entities affected by a hierarchy operation.
+struct HierarchyState {
+    parents: Vec<(Entity, Vec<Entity>)>,
+    orphans: Vec<Entity>,
+}
+
+
+impl HierarchyState {
+    fn capture(world: &World, parents: &[Entity], children: &[Entity]) -> Self {
+        Self {
+            parents: parents
+                .iter()
+                .map(|parent| (*parent, children_of(world, *parent)))
+                .collect(),
+            orphans: children
+                .iter()
+                .copied()
+                .filter(|child| world.get::<Parent>(*child).is_none())
+                .collect(),
+        }
+    }
+
+
+    fn map_entities(&mut self, map: &UndoEntityMap) {
+        for (parent, children) in &mut self.parents {
+            map.map(parent);
+            children.iter_mut().for_each(|child| map.map(child));
+        }
+        self.orphans.iter_mut().for_each(|orphan| map.map(orphan));
+    }
+
+
+    fn restore(&self, world: &mut World) {
+        for orphan in &self.orphans {
+            if let Some(mut orphan) = world.get_entity_mut(*orphan) {
+                orphan.remove_parent();
+            }
+        }
+
+        for (parent, children) in &self.parents {
+            if world.get_entity(*parent).is_none() {
+                continue;
+            }
+            let removed = children_of(world, *parent)
+                .into_iter()
+                .filter(|child| !children.contains(child))
+                .collect::<Vec<_>>();
+            let children = children
+                .iter()
+                .copied()
+                .filter(|child| world.get_entity(*child).is_some())
+                .collect::<Vec<_>>();
+
+            let mut parent = world.entity_mut(*parent);
+            parent.remove_children(&removed);
+            if !children.is_empty() {
+                parent.push_children(&children);
+            }
+        }
+    }
+}
+
+
+/// Restores the parents and the order of children the entities had before and after a hierarchy operation.
+pub(crate) struct UndoableHierarchy {
+    before: HierarchyState,
+    after: HierarchyState,
+}
+
+
+impl UndoableCommand for UndoableHierarchy {
+    #[inline]
+    fn apply(&mut self, world: &mut World) {
+        self.after.restore(world);
+    }
+
+
+    #[inline]
+    fn revert(&mut self, world: &mut World) {
+        self.before.restore(world);
+    }
+
+
+    #[inline]
+    fn map_entities(&mut self, map: &UndoEntityMap) {
+        self.before.map_entities(map);
+        self.after.map_entities(map);
+    }
+}
+
+
+/// Captures the hierarchy around `children`, changes it with `change` and registers the change in the history.
+///
+/// `parents` are the parents the children are moved to; their former parents are captured as well.
+pub(crate) struct ChangeHierarchy<F> {
+    parents: Vec<Entity>,
+    children: Vec<Entity>,
+    change: F,
+}
+
+
+impl<F: FnOnce(&mut World) + Send + 'static> ChangeHierarchy<F> {
+    #[inline(always)]
+    pub const fn new(parents: Vec<Entity>, children: Vec<Entity>, change: F) -> Self {
+        Self {
+            parents,
+            children,
+            change,
+        }
+    }
+}
+
+
+impl<F: FnOnce(&mut World) + Send + 'static> Command for ChangeHierarchy<F> {
+    fn apply(self, world: &mut World) {
+        let mut parents = self.parents;
+        for parent in self.children.iter().filter_map(|child| world.get::<Parent>(*child)) {
+            if !parents.contains(&parent.get()) {
+                parents.push(parent.get());
+            }
+        }
+
+        let before = HierarchyState::capture(world, &parents, &self.children);
+        (self.change)(world);
+        let after = HierarchyState::capture(world, &parents, &self.children);
+        register_command(world, UndoableHierarchy {
+            before,
+            after,
+        });
+    }
+}
+
+
+#[inline]
+fn children_of(world: &World, parent: Entity) -> Vec<Entity> {
+    world
+        .get::<Children>(parent)
+        .map(|children| children.to_vec())
+        .unwrap_or_default()
+}
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use bevy::ecs::component::Tick;
+use bevy::ecs::query::{QueryEntityError, QuerySingleError, ReadOnlyWorldQuery};
+use bevy::ecs::system::{Deferred, SystemBuffer, SystemMeta, SystemParam, SystemState};
+use bevy::prelude::{Component, DetectChanges, Entity, Mut, Query, World};
+
+use crate::command::component::{UndoableComponent, UndoShadow};
+use crate::command::UndoCommandEvent;
+use crate::undo_event::UndoScheduler;
+
+/// Behaves like `Query<&mut C, F>`, but the value of each component is snapshotted
+/// the first time it is accessed mutably.
+///
+/// When the system's commands are applied, the changes of all accessed entities are registered in the history as one step;
+/// entities whose component was not written to are left out.
+#[derive(SystemParam)]
+pub struct UndoQuery<'w, 's, C: Component + Clone, F: ReadOnlyWorldQuery + 'static = ()> {
+    query: Query<'w, 's, (Entity, &'static mut C), F>,
+    snapshots: Deferred<'s, ComponentSnapshots<C>>,
+}
+
+
+impl<'w, 's, C: Component + Clone, F: ReadOnlyWorldQuery + 'static> UndoQuery<'w, 's, C, F> {
+    #[inline]
+    pub fn get(&self, entity: Entity) -> Result<&C, QueryEntityError> {
+        self.query.get(entity).map(|(_, component)| component)
+    }
+
+
+    #[inline]
+    pub fn get_mut(&mut self, entity: Entity) -> Result<Mut<'_, C>, QueryEntityError> {
+        let (entity, component) = self.query.get_mut(entity)?;
+        Ok(self.snapshots.snapshot(entity, component))
+    }
+
+
+    #[inline]
+    pub fn get_single_mut(&mut self) -> Result<Mut<'_, C>, QuerySingleError> {
+        let (entity, component) = self.query.get_single_mut()?;
+        Ok(self.snapshots.snapshot(entity, component))
+    }
+
+
+    #[inline]
+    pub fn single_mut(&mut self) -> Mut<'_, C> {
+        self.get_single_mut().unwrap()
+    }
+
+
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item=(Entity, &C)> {
+        self.query.iter()
+    }
+
+
+    /// Returns the components, snapshotting each one when the iterator yields it.
+    #[inline]
+    pub fn iter_mut(&mut self) -> impl Iterator<Item=(Entity, Mut<'_, C>)> {
+        let snapshots = &mut *self.snapshots;
+        self
+            .query
+            .iter_mut()
+            .map(move |(entity, component)| (entity, snapshots.snapshot(entity, component)))
+    }
+}
+
+
+/// The value of each accessed component, with the tick it was last changed at when it was snapshotted.
+pub(crate) struct ComponentSnapshots<C>(HashMap<Entity, (C, Tick)>);
+
+
+impl<C: Component + Clone> ComponentSnapshots<C> {
+    #[inline]
+    fn snapshot<'a>(&mut self, entity: Entity, component: Mut<'a, C>) -> Mut<'a, C> {
+        self.0.entry(entity).or_insert_with(|| (component.clone(), component.last_changed()));
+        component
+    }
+}
+
+
+impl<C> Default for ComponentSnapshots<C> {
+    #[inline(always)]
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+
+impl<C: Component + Clone> SystemBuffer for ComponentSnapshots<C> {
+    fn apply(&mut self, _: &SystemMeta, world: &mut World) {
+        if self.0.is_empty() {
+            return;
+        }
+
+        let changes = self
+            .0
+            .drain()
+            .filter_map(|(entity, (before, tick))| {
+                let mut entity = world.get_entity_mut(entity)?;
+                if entity.get_change_ticks::<C>()?.last_changed_tick() == tick {
+                    return None;
+                }
+                let after = entity.get::<C>()?.clone();
+                UndoShadow::suppress(&mut entity, &after);
+                Some(UndoableComponent::new(entity.id(), Some(before), Some(after)))
+            })
+            .collect::<Vec<_>>();
+
+        let mut state = SystemState::<UndoScheduler<UndoCommandEvent>>::new(world);
+        state.get_mut(world).register_commands(changes);
+        state.apply(world);
+    }
+}
@@ -0,0 +1,162 @@
+use std::any::TypeId;
+
+use bevy::ecs::system::Command;
+use bevy::log::warn;
+use bevy::prelude::{AppTypeRegistry, Entity, ReflectComponent, World};
+use bevy::reflect::{GetPath, Reflect};
+
+use crate::command::{register_command, UndoableCommand};
+use crate::remap::UndoEntityMap;
+
+/// Sets a field of a reflected component, addressed by a path such as `"translation.x"`.
+///
+/// A single entry type covers every property edit, so an inspector does not need
+/// a dedicated undo event for each field.
+/// The component has to be registered with `#[reflect(Component)]` in the [`AppTypeRegistry`].
+pub struct UndoablePropertyEdit {
+    entity: Entity,
+    component: TypeId,
+    path: String,
+    before: Box<dyn Reflect>,
+    after: Box<dyn Reflect>,
+}
+
+
+impl UndoablePropertyEdit {
+    #[inline(always)]
+    pub fn new(
+        entity: Entity,
+        component: TypeId,
+        path: impl Into<String>,
+        before: Box<dyn Reflect>,
+        after: Box<dyn Reflect>,
+    ) -> Self {
+        Self {
+            entity,
+            component,
+            path: path.into(),
+            before,
+            after,
+        }
+    }
+
+
+    #[inline(always)]
+    pub const fn entity(&self) -> Entity {
+        self.entity
+    }
+
+
+    #[inline(always)]
+    pub const fn component(&self) -> TypeId {
+        self.component
+    }
+
+
+    #[inline(always)]
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+
+    #[inline(always)]
+    pub fn before(&self) -> &dyn Reflect {
+        self.before.as_ref()
+    }
+
+
+    #[inline(always)]
+    pub fn after(&self) -> &dyn Reflect {
+        self.after.as_ref()
+    }
+}
+
+
+impl UndoableCommand for UndoablePropertyEdit {
+    #[inline]
+    fn apply(&mut self, world: &mut World) {
+        write_property(world, self.entity, self.component, &self.path, self.after.as_ref());
+    }
+
+
+    #[inline]
+    fn revert(&mut self, world: &mut World) {
+        write_property(world, self.entity, self.component, &self.path, self.before.as_ref());
+    }
+
+
+    #[inline]
+    fn map_entities(&mut self, map: &UndoEntityMap) {
+        map.map(&mut self.entity);
+    }
+}
+
+
+/// Captures the current value of the property, sets it to `value` and registers the edit in the history.
+///
+/// Nothing is registered if the property can not be read, or its type differs from the value.
+pub(crate) struct EditProperty {
+    entity: Entity,
+    component: TypeId,
+    path: String,
+    value: Box<dyn Reflect>,
+}
+
+
+impl EditProperty {
+    #[inline(always)]
+    pub const fn new(entity: Entity, component: TypeId, path: String, value: Box<dyn Reflect>) -> Self {
+        Self {
+            entity,
+            component,
+            path,
+            value,
+        }
+    }
+}
+
+
+impl Command for EditProperty {
+    fn apply(self, world: &mut World) {
+        let Some(before) = read_property(world, self.entity, self.component, &self.path) else {
+            warn!("the property {:?} of {:?} can not be read", self.path, self.entity);
+            return;
+        };
+        if before.type_name() != self.value.type_name() {
+            warn!("the property {:?} is {}, but the value is {}", self.path, before.type_name(), self.value.type_name());
+            return;
+        }
+        let mut command = UndoablePropertyEdit::new(self.entity, self.component, self.path, before, self.value);
+        command.apply(world);
+        register_command(world, command);
+    }
+}
+
+
+fn read_property(world: &World, entity: Entity, component: TypeId, path: &str) -> Option<Box<dyn Reflect>> {
+    let registry = world.get_resource::<AppTypeRegistry>()?.read();
+    let reflect = registry.get_type_data::<ReflectComponent>(component)?;
+    let component = reflect.reflect(world.get_entity(entity)?)?;
+    component
+        .reflect_path(path)
+        .ok()
+        .map(|field| field.clone_value())
+}
+
+
+fn write_property(world: &mut World, entity: Entity, component: TypeId, path: &str, value: &dyn Reflect) {
+    let Some(registry) = world.get_resource::<AppTypeRegistry>().cloned() else { return; };
+    let registry = registry.read();
+    let Some(reflect) = registry.get_type_data::<ReflectComponent>(component) else {
+        warn!("the component {component:?} is not registered with #[reflect(Component)]");
+        return;
+    };
+    let Some(mut entity) = world.get_entity_mut(entity) else { return; };
+    let Some(mut component) = reflect.reflect_mut(&mut entity) else { return; };
+
+    match component.reflect_path_mut(path) {
+        Ok(field) if field.type_name() == value.type_name() => field.apply(value),
+        Ok(field) => warn!("the property {path:?} is {}, but the value is {}", field.type_name(), value.type_name()),
+        Err(e) => warn!("failed to access the property {path:?}: {e}"),
+    }
+}
@@ -0,0 +1,78 @@
+use std::ops::{Deref, DerefMut};
+
+use bevy::ecs::system::{Deferred, SystemBuffer, SystemMeta, SystemParam};
+use bevy::prelude::{ResMut, Resource, World};
+
+use crate::command::{register_command, UndoableCommand};
+
+/// Sets the resource to `after` on apply and to `before` on revert.
+pub(crate) struct UndoableResource<T> {
+    before: T,
+    after: T,
+}
+
+
+impl<T: Resource + Clone> UndoableCommand for UndoableResource<T> {
+    #[inline]
+    fn apply(&mut self, world: &mut World) {
+        world.insert_resource(self.after.clone());
+    }
+
+
+    #[inline]
+    fn revert(&mut self, world: &mut World) {
+        world.insert_resource(self.before.clone());
+    }
+}
+
+
+/// Behaves like [`ResMut`], but the first mutable dereference snapshots the value of the resource,
+/// and the change is registered in the history as a new step when the system's commands are applied.
+///
+/// Since it shares the undo counter, the step interleaves correctly with the other undo entries.
+#[derive(SystemParam)]
+pub struct UndoResMut<'w, 's, T: Resource + Clone> {
+    resource: ResMut<'w, T>,
+    snapshot: Deferred<'s, ResourceSnapshot<T>>,
+}
+
+
+impl<'w, 's, T: Resource + Clone> Deref for UndoResMut<'w, 's, T> {
+    type Target = T;
+
+    #[inline(always)]
+    fn deref(&self) -> &Self::Target {
+        &self.resource
+    }
+}
+
+
+impl<'w, 's, T: Resource + Clone> DerefMut for UndoResMut<'w, 's, T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        if self.snapshot.0.is_none() {
+            self.snapshot.0 = Some(self.resource.clone());
+        }
+        &mut self.resource
+    }
+}
+
+
+pub(crate) struct ResourceSnapshot<T>(Option<T>);
+
+
+impl<T> Default for ResourceSnapshot<T> {
+    #[inline(always)]
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
+
+impl<T: Resource + Clone> SystemBuffer for ResourceSnapshot<T> {
+    fn apply(&mut self, _: &SystemMeta, world: &mut World) {
+        let Some(before) = self.0.take() else { return; };
+        let Some(after) = world.get_resource::<T>().cloned() else { return; };
+        register_command(world, UndoableResource { before, after });
+    }
+}
@@ -0,0 +1,107 @@
+use std::marker::PhantomData;
+use std::ops::Range;
+
+use bevy::ecs::event::{EventId, Events};
+use bevy::prelude::{Event, ResMut, Resource};
+
+/// Where an event of an undo event type comes from.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum UndoOrigin {
+    /// The event was sent by the app, not by this crate.
+    #[default]
+    User,
+
+    /// The event was sent because its step or entry was undone.
+    Undo,
+
+    /// The event was sent because its step was redone.
+    Redo,
+
+    /// The event was sent because its step was undone or redone on the way to the step passed to
+    /// [`UndoRequester::goto`](crate::request::UndoRequester::goto).
+    Replay,
+}
+
+
+/// Tells the origin of the events of type `E`, so that readers do not register the events sent by undo or redo again.
+///
+/// Events are identified by the ids given by [`EventReader::iter_with_id`](bevy::prelude::EventReader::iter_with_id).
+/// The resource is added with [`AppUndoEx::add_undo_event`](crate::extension::AppUndoEx::add_undo_event).
+#[derive(Resource, Debug)]
+pub struct UndoContext<E> {
+    sent: Vec<(Range<usize>, UndoOrigin)>,
+    _marker: PhantomData<fn() -> E>,
+}
+
+
+impl<E: Event> UndoContext<E> {
+    /// Returns the origin of the event, or [`UndoOrigin::User`] if it was not sent by undo or redo.
+    pub fn origin(&self, id: EventId<E>) -> UndoOrigin {
+        self
+            .sent
+            .iter()
+            .find(|(ids, _)| ids.contains(&id.id))
+            .map(|(_, origin)| *origin)
+            .unwrap_or_default()
+    }
+
+
+    /// Returns true if the event was sent by undo or redo, including [`UndoOrigin::Replay`].
+    #[inline]
+    pub fn is_undo_or_redo(&self, id: EventId<E>) -> bool {
+        self.origin(id) != UndoOrigin::User
+    }
+
+
+    /// Sends the events and records their ids with the origin, and returns the number of events sent.
+    pub(crate) fn send(&mut self, events: &mut Events<E>, origin: UndoOrigin, batch: Vec<E>) -> usize {
+        let oldest = events.oldest_id();
+        self.sent.retain(|(ids, _)| oldest < ids.end);
+        if batch.is_empty() {
+            return 0;
+        }
+
+        let start = oldest + events.len();
+        let end = start + batch.len();
+        events.extend(batch);
+        self.sent.push((start..end, origin));
+        end - start
+    }
+}
+
+
+impl<E> Default for UndoContext<E> {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            sent: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+
+/// Present while the events sent by undo or redo may still be read, which is the frame they are sent in and the next one.
+///
+/// Tracking systems do not record the changes made during this time, since they are made by the readers of those events.
+#[derive(Resource, Debug, Default, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct UndoApplying(usize);
+
+
+impl UndoApplying {
+    #[inline(always)]
+    pub fn start(&mut self) {
+        self.0 = 2;
+    }
+
+
+    #[inline(always)]
+    pub const fn is_active(&self) -> bool {
+        0 < self.0
+    }
+}
+
+
+pub(crate) fn undo_applying_system(mut applying: ResMut<UndoApplying>) {
+    applying.0 = applying.0.saturating_sub(1);
+}
@@ -9,12 +9,6 @@ pub(crate) struct UndoCounter(usize);
 
 
 impl UndoCounter {
-    #[inline(always)]
-    pub const fn new() -> Self {
-        Self(0)
-    }
-
-
     #[inline(always)]
     pub fn increment(&mut self) {
         self.0 += 1;
@@ -23,13 +17,29 @@ impl UndoCounter {
 
     #[inline(always)]
     pub fn decrement(&mut self) {
-        self.0 = self.0.checked_sub(1).unwrap_or_default();
+        self.0 = self.0.saturating_sub(1);
     }
 }
 
 
+/// Issues the ids of the entries, which are never reused,
+/// so that an [`UndoHandle`](crate::undo_event::UndoHandle) can not refer to an entry registered after its own.
+#[derive(Resource, Default, Debug, Copy, Clone)]
+#[repr(transparent)]
+pub(crate) struct UndoEntryIds(usize);
+
 
-impl AddAssign<ReserveCounter> for UndoCounter{
+impl UndoEntryIds {
+    #[inline(always)]
+    pub fn issue(&mut self) -> usize {
+        self.0 += 1;
+        self.0
+    }
+}
+
+
+impl AddAssign<ReserveCounter> for UndoCounter {
+    #[inline(always)]
     fn add_assign(&mut self, rhs: ReserveCounter) {
         self.0 += *rhs;
     }
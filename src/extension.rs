@@ -1,10 +1,22 @@
-use bevy::app::{App, Update};
-use bevy::prelude::{Event, EventReader, EventWriter, IntoSystemConfigs, ResMut};
-use crate::{CommitReservationsEvent, DecrementCounterEvent, UndoRegisteredArea};
-use crate::prelude::UndoRequester;
-use crate::request::RequestUndoEvent;
-use crate::reserve::{ReserveCounter, UndoReservedArea, UndoReserveEvent};
+use std::any::TypeId;
+
+use bevy::app::{App, PostUpdate, Update};
+use bevy::asset::Asset;
+use bevy::ecs::event::Events;
+use bevy::prelude::{Component, Event, EventReader, IntoSystemConfigs, Mut, Res, ResMut, Resource, World};
+use crate::{CommitReservationsEvent, UndoRegisteredArea};
+use crate::asset::{undo_asset_event_system, UndoAssetEvent};
+use crate::command::component::track_component_system;
+use crate::context::{UndoContext, UndoOrigin};
+use crate::progress::UndoCompletions;
+use crate::remap::{map_undo_entities_system, UndoMapEntities, UndoRemapping};
+use crate::request::UndoDirection;
+use crate::reserve::{ReserveCounter, UndoReservedArea};
+use crate::tree::{UndoBranchEvent, UndoTree};
 use crate::undo_event::UndoEvent;
+use crate::validate::{UndoInvalidEntryPolicy, UndoTargets, UndoValidation};
+use crate::request::request_queue_system;
+use crate::veto::{UndoVerdict, UndoVetoes};
 
 
 pub trait AppUndoEx {
@@ -12,91 +24,319 @@ pub trait AppUndoEx {
     ///
     /// In order to use undo-action, you must call [`UndoScheduler::register`](UndoScheduler::register).
     /// then call [`UndoRequester::undo`](UndoRequester::undo) when you need.
-    fn add_undo_event<T: Event + Clone>(&mut self) -> &mut App;
+    ///
+    /// Events are moved into the history and moved out when they are undone, so `T` does not need to be [`Clone`].
+    fn add_undo_event<T: Event>(&mut self) -> &mut App;
+
+
+    /// Keep the undone steps as an [`UndoBranch`](crate::tree::UndoBranch) when a new event is registered after an undo,
+    /// instead of discarding them.
+    ///
+    /// The branches can be listed via [`UndoTree`] and switched to with [`UndoRequester::switch_branch`](crate::request::UndoRequester::switch_branch).
+    fn enable_undo_tree(&mut self) -> &mut App;
+
+
+    /// Record the changes of components of type `C` as undo entries automatically.
+    ///
+    /// The value last seen on each entity is kept as a shadow copy; when the component is changed,
+    /// the shadow copy is registered as the value to restore on undo. All changes detected in the same frame
+    /// are registered as one step, in [`PostUpdate`].
+    ///
+    /// Changes made by undo or redo, or via [`EntityCommandsUndoEx`](crate::command::EntityCommandsUndoEx),
+    /// are not recorded again. This includes the changes made by the app's own readers of undo events,
+    /// so changes made in the frame a step is undone or redone, or in the next one, are not recorded.
+    /// Removing the component is not recorded.
+    fn track_undo_component<C: Component + Clone>(&mut self) -> &mut App;
+
+
+    /// Setup the app to undo edits of assets of type `T` made through [`UndoAssets`](crate::asset::UndoAssets).
+    ///
+    /// Each entry keeps the value the asset had before the edit, and restores it by the handle on undo.
+    fn add_undo_asset<T: Asset + Clone>(&mut self) -> &mut App;
+
+
+    /// Rewrite the entities held by the undo events of type `T` when they are restored with new ids,
+    /// such as after undoing [`despawn_undoable`](crate::command::EntityCommandsUndoEx::despawn_undoable).
+    /// A request that goes through several steps waits a frame after each step that restores entities,
+    /// so that the later steps are rewritten before they are sent.
+    ///
+    /// `T` has to be set up with [`add_undo_event`](AppUndoEx::add_undo_event) as well.
+    fn map_undo_entities<T: Event + UndoMapEntities>(&mut self) -> &mut App;
+
+
+    /// Check that the [`targets`](UndoTargets::targets) of the undo events of type `T` still exist
+    /// before the events are sent on undo or redo.
+    ///
+    /// Invalid entries are skipped or dropped according to `policy`, and reported by [`UndoInvalidEntryEvent`](crate::validate::UndoInvalidEntryEvent).
+    fn validate_undo_targets<T: Event + UndoTargets>(&mut self, policy: UndoInvalidEntryPolicy) -> &mut App;
+
+
+    /// Add a hook that is asked before each undo or redo request is applied, with the step and its direction.
+    ///
+    /// The request is applied only if all hooks return [`UndoVerdict::Allow`]; otherwise it is rejected or postponed
+    /// to the next frame, and an [`UndoVetoedEvent`](crate::veto::UndoVetoedEvent) with the reason is sent.
+    fn add_undo_veto(&mut self, hook: impl Fn(&World, UndoDirection, usize) -> UndoVerdict + Send + Sync + 'static) -> &mut App;
+
+
+    /// Add a veto hook that is asked for each undo event of type `T` in the requested step.
+    ///
+    /// When the step is redone, the hook is given the redo-events. See [`add_undo_veto`](AppUndoEx::add_undo_veto).
+    fn add_undo_event_veto<T: Event>(&mut self, hook: impl Fn(&World, &T) -> UndoVerdict + Send + Sync + 'static) -> &mut App;
+
+
+    /// Require the undo events of type `T` to be acknowledged via [`UndoInProgress::complete`](crate::progress::UndoInProgress::complete)
+    /// after they are applied, such as when they start an animation.
+    ///
+    /// While a step holding them is being undone or redone, further requests wait according to the [`UndoQueuePolicy`](crate::progress::UndoQueuePolicy).
+    fn defer_undo_completion<T: Event>(&mut self) -> &mut App;
 }
 
 
 impl AppUndoEx for App {
-    fn add_undo_event<E: Event + Clone>(&mut self) -> &mut App {
+    fn add_undo_event<E: Event>(&mut self) -> &mut App {
         self.add_event::<E>();
-        self.add_event::<UndoEvent<E>>();
-        self.add_event::<UndoReserveEvent<E>>();
         self.init_resource::<UndoRegisteredArea<E>>();
-        self.init_resource::<UndoRegisteredArea<UndoReserveEvent<E>>>();
         self.init_resource::<UndoReservedArea<E>>();
+        self.init_resource::<UndoContext<E>>();
         self.init_resource::<ReserveCounter>();
+        self.init_resource::<UndoAreas>();
+        self.world.resource_mut::<UndoAreas>().add::<E>();
         self.add_systems(Update, (
-               reserve_event_system::<E>,
+            branch_system::<E>,
             register_all_reserved_events_system::<E>,
-            push_undo_event_system::<E>,
-            request_undo_event_system::<E>,
-            request_undo_event_system::<E>,
-            request_undo_event_system::<UndoReserveEvent<E>>,
+        ).chain().before(request_queue_system));
+        self
+    }
+
+
+    fn enable_undo_tree(&mut self) -> &mut App {
+        self.init_resource::<UndoTree>();
+        self.world.resource_mut::<UndoTree>().enable();
+        self
+    }
+
+
+    #[inline]
+    fn track_undo_component<C: Component + Clone>(&mut self) -> &mut App {
+        self.add_systems(PostUpdate, track_component_system::<C>)
+    }
+
+
+    #[inline]
+    fn add_undo_asset<T: Asset + Clone>(&mut self) -> &mut App {
+        self
+            .add_undo_event::<UndoAssetEvent<T>>()
+            .add_systems(Update, undo_asset_event_system::<T>)
+    }
+
+
+    fn map_undo_entities<E: Event + UndoMapEntities>(&mut self) -> &mut App {
+        self.init_resource::<UndoRemapping>();
+        self.world.resource_mut::<UndoRemapping>().push_mapped(TypeId::of::<E>());
+        self.add_systems(Update, map_undo_entities_system::<E>.before(branch_system::<E>).before(request_queue_system))
+    }
+
+
+    #[inline]
+    fn validate_undo_targets<E: Event + UndoTargets>(&mut self, policy: UndoInvalidEntryPolicy) -> &mut App {
+        self.insert_resource(UndoValidation::<E>::new(policy))
+    }
+
+
+    #[inline]
+    fn add_undo_veto(&mut self, hook: impl Fn(&World, UndoDirection, usize) -> UndoVerdict + Send + Sync + 'static) -> &mut App {
+        self.init_resource::<UndoVetoes>();
+        self.world.resource_mut::<UndoVetoes>().push(hook);
+        self
+    }
+
 
-        ).chain());
+    fn add_undo_event_veto<E: Event>(&mut self, hook: impl Fn(&World, &E) -> UndoVerdict + Send + Sync + 'static) -> &mut App {
+        self.add_undo_veto(move |world, direction, no| {
+            let Some(registered_area) = world.get_resource::<UndoRegisteredArea<E>>() else { return UndoVerdict::Allow; };
+            registered_area
+                .step_events(no, direction == UndoDirection::Redo)
+                .map(|event| hook(world, event))
+                .find(|verdict| *verdict != UndoVerdict::Allow)
+                .unwrap_or(UndoVerdict::Allow)
+        })
+    }
+
+
+    fn defer_undo_completion<E: Event>(&mut self) -> &mut App {
+        self.init_resource::<UndoCompletions>();
+        self.world.resource_mut::<UndoCompletions>().push(TypeId::of::<E>());
         self
     }
 }
 
 
-fn register_all_reserved_events_system<E: Event + Clone>(
-    mut er: EventReader<CommitReservationsEvent>,
-    mut reserved_area: ResMut<UndoReservedArea<E>>,
-    mut registered_reserve_event_area: ResMut<UndoRegisteredArea<UndoReserveEvent<E>>>,
-) {
-    while let Some(CommitReservationsEvent(counter)) = er.iter().next() {
-        reserved_area.0.sort_by(|e1, e2| e2.reserve_no.partial_cmp(&e1.reserve_no).unwrap());
+/// The operations on the registered area of each undo event type.
+///
+/// They are called by [`request_queue_system`] with exclusive access to the world,
+/// so that the entries of a step are moved at the same time as the undo counter.
+#[derive(Resource, Default)]
+pub(crate) struct UndoAreas(Vec<UndoAreaOps>);
+
+
+#[derive(Copy, Clone)]
+pub(crate) struct UndoAreaOps {
+    pub type_id: TypeId,
+
+    /// Undoes the step and returns the number of events sent.
+    pub undo: fn(&mut World, usize, UndoOrigin) -> usize,
+
+    /// Redoes the step and returns the number of events sent.
+    pub redo: fn(&mut World, usize, UndoOrigin) -> usize,
+
+    /// Undoes the entries of the step without keeping them for redo, and returns the number of events sent.
+    pub undo_entry: fn(&mut World, usize, UndoOrigin) -> usize,
+
+    /// Returns true if the step has entries that can be redone.
+    pub has_redo: fn(&mut World, usize) -> bool,
+
+    /// Returns true if the step is undone and redone together with the previous one.
+    pub is_chained: fn(&mut World, usize) -> bool,
 
-        while let Some(event) = reserved_area.pop_front() {
-            registered_reserve_event_area.push(UndoEvent {
-                inner: event.clone(),
-                no: **counter + event.reserve_no,
+    /// Returns the step of the entry with the id, if it can be undone.
+    pub find_entry: fn(&mut World, usize) -> Option<usize>,
+}
+
+
+impl UndoAreas {
+    fn add<E: Event>(&mut self) {
+        let type_id = TypeId::of::<E>();
+        if self.0.iter().all(|ops| ops.type_id != type_id) {
+            self.0.push(UndoAreaOps {
+                type_id,
+                undo: undo_step::<E>,
+                redo: redo_step::<E>,
+                undo_entry: undo_entry::<E>,
+                has_redo: has_redo::<E>,
+                is_chained: is_chained::<E>,
+                find_entry: find_entry::<E>,
             });
         }
     }
+
+
+    #[inline]
+    pub fn ops(&self) -> Vec<UndoAreaOps> {
+        self.0.clone()
+    }
 }
 
 
-fn push_undo_event_system<E: Event + Clone>(
-    mut er: EventReader<UndoEvent<E>>,
+fn branch_system<E: Event>(
+    events: Res<Events<UndoBranchEvent>>,
     mut registered_area: ResMut<UndoRegisteredArea<E>>,
 ) {
-    for e in er.iter() {
-        registered_area.push(e.clone());
-    }
+    registered_area.sync_branches(&events);
 }
 
 
-fn request_undo_event_system<E: Event + Clone>(
-    mut er: EventReader<RequestUndoEvent>,
-    mut ew: EventWriter<E>,
-    mut decrement_writer: EventWriter<DecrementCounterEvent>,
+fn register_all_reserved_events_system<E: Event>(
+    mut er: EventReader<CommitReservationsEvent>,
+    mut reserved_area: ResMut<UndoReservedArea<E>>,
     mut registered_area: ResMut<UndoRegisteredArea<E>>,
 ) {
-    for RequestUndoEvent(counter) in er.iter() {
-        while let Some(undo) = registered_area.pop_if_has_latest(counter) {
-            ew.send(undo);
-            decrement_writer.send(DecrementCounterEvent);
+    for CommitReservationsEvent(counter) in er.iter() {
+        for event in reserved_area.drain() {
+            registered_area.push(UndoEvent {
+                inner: event.inner,
+                redo: event.redo,
+                no: counter + event.reserve_no,
+                id: event.id,
+                chained: 1 < event.reserve_no,
+                meta: event.meta,
+            });
         }
     }
 }
 
 
-fn reserve_event_system<E: Event + Clone>(
-    mut er: EventReader<UndoReserveEvent<E>>,
-    mut ew: EventWriter<E>,
-    mut undo_event_writer: UndoRequester,
-) {
-    if er.is_empty() {
-        return;
-    }
+fn undo_step<E: Event>(world: &mut World, no: usize, origin: UndoOrigin) -> usize {
+    apply_step::<E>(world, no, Take::Undo, origin)
+}
 
-    for event in er.iter() {
-        ew.send(event.inner.clone());
-        if 1 < event.reserve_no {
-            undo_event_writer.undo();
-        }
-    }
+
+fn redo_step<E: Event>(world: &mut World, no: usize, origin: UndoOrigin) -> usize {
+    apply_step::<E>(world, no, Take::Redo, origin)
 }
 
 
+fn undo_entry<E: Event>(world: &mut World, no: usize, origin: UndoOrigin) -> usize {
+    apply_step::<E>(world, no, Take::Entry, origin)
+}
+
+
+fn has_redo<E: Event>(world: &mut World, no: usize) -> bool {
+    inspect::<E, _>(world, |registered_area| registered_area.has_redo(no))
+}
+
+
+fn is_chained<E: Event>(world: &mut World, no: usize) -> bool {
+    inspect::<E, _>(world, |registered_area| registered_area.is_chained(no))
+}
+
+
+fn find_entry<E: Event>(world: &mut World, id: usize) -> Option<usize> {
+    inspect::<E, _>(world, |registered_area| registered_area.find_entry(id))
+}
+
+
+/// Calls `f` with the registered area after applying the pending branch events.
+fn inspect<E: Event, R>(world: &mut World, f: impl FnOnce(&UndoRegisteredArea<E>) -> R) -> R {
+    world.resource_scope(|world, mut registered_area: Mut<UndoRegisteredArea<E>>| {
+        registered_area.sync_branches(world.resource::<Events<UndoBranchEvent>>());
+        f(&registered_area)
+    })
+}
+
+
+/// How the events of a step are taken out of the registered area.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Take {
+    Undo,
+    Redo,
+    Entry,
+}
+
+
+/// Takes the events of the step out of the registered area and sends them,
+/// applying the [`UndoValidation`] of the event type if it has been set up.
+///
+/// Returns the number of events sent.
+fn apply_step<E: Event>(
+    world: &mut World,
+    no: usize,
+    take: Take,
+    origin: UndoOrigin,
+) -> usize {
+    let redo = take == Take::Redo;
+    let mut reports = Vec::new();
+    let events = world.resource_scope(|world, mut registered_area: Mut<UndoRegisteredArea<E>>| {
+        registered_area.sync_branches(world.resource::<Events<UndoBranchEvent>>());
+        let validation = world.get_resource::<UndoValidation<E>>();
+        if let Some(validation) = validation {
+            validation.drop_invalid(&mut registered_area, no, redo, world.entities(), &mut reports);
+            if take == Take::Undo {
+                validation.keep_skipped(&mut registered_area, no, world.entities(), &mut reports);
+            }
+        }
+        let events = match take {
+            Take::Undo => registered_area.undo(no),
+            Take::Redo => registered_area.redo(no),
+            Take::Entry => registered_area.undo_entry(no),
+        };
+        match validation {
+            Some(validation) => validation.validate(no, events, world.entities(), &mut reports),
+            None => events
+        }
+    });
+    world.send_event_batch(reports);
+
+    world.resource_scope(|world, mut context: Mut<UndoContext<E>>| {
+        context.send(&mut world.resource_mut::<Events<E>>(), origin, events)
+    })
+}
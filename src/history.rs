@@ -0,0 +1,132 @@
+use std::time::Duration;
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::{Event, Res, Resource};
+
+use crate::reserve::UndoReservedArea;
+use crate::undo_event::{UndoEvent, UndoHandle};
+use crate::UndoRegisteredArea;
+
+/// Identifies who or what registered an undo entry.
+///
+/// While this resource exists, every entry registered or reserved through [`UndoScheduler`](crate::undo_event::UndoScheduler)
+/// records a copy of it in [`UndoEntryMeta::author`].
+#[derive(Resource, Debug, Clone, Eq, PartialEq, Hash)]
+#[repr(transparent)]
+pub struct UndoAuthor(pub String);
+
+
+impl UndoAuthor {
+    #[inline(always)]
+    pub fn new(author: impl Into<String>) -> Self {
+        Self(author.into())
+    }
+}
+
+
+/// Metadata recorded when an undo entry is registered or reserved.
+#[derive(Debug, Default, Clone, Eq, PartialEq, Hash)]
+pub struct UndoEntryMeta {
+    /// [`Time::elapsed`](bevy::time::Time::elapsed) at the moment of registration.
+    ///
+    /// This is zero if the app does not have the [`Time`](bevy::time::Time) resource.
+    pub elapsed: Duration,
+
+    /// [`FrameCount`](bevy::core::FrameCount) at the moment of registration.
+    ///
+    /// This is zero if the app does not have the [`FrameCount`](bevy::core::FrameCount) resource.
+    pub frame: u32,
+
+    /// The [`UndoAuthor`] that was present at the moment of registration.
+    pub author: Option<UndoAuthor>,
+}
+
+
+/// A view of a single entry held in the history.
+#[derive(Debug)]
+pub struct UndoHistoryEntry<'a, E> {
+    /// The step of the undo counter this entry belongs to.
+    pub no: usize,
+    pub meta: &'a UndoEntryMeta,
+    pub event: &'a E,
+    id: usize,
+}
+
+
+impl<'a, E> UndoHistoryEntry<'a, E> {
+    /// Returns the handle which can be passed to [`UndoRequester::undo_entry`](crate::request::UndoRequester::undo_entry).
+    #[inline(always)]
+    pub const fn handle(&self) -> UndoHandle {
+        UndoHandle { no: self.no, id: self.id }
+    }
+}
+
+
+/// Read-only access to the undo history of events of type `E`.
+#[derive(SystemParam)]
+pub struct UndoHistory<'w, E: Event> {
+    registered: Res<'w, UndoRegisteredArea<E>>,
+    reserved: Res<'w, UndoReservedArea<E>>,
+}
+
+
+impl<'w, E: Event> UndoHistory<'w, E> {
+    /// Returns the entries that can be undone ordered from oldest to latest,
+    /// including the ones that were grouped via reservations.
+    #[inline]
+    pub fn iter(&self) -> impl Iterator<Item=UndoHistoryEntry<'_, E>> {
+        self.registered.undo.iter().map(UndoHistoryEntry::from)
+    }
+
+
+    /// Returns the entries that were undone and can be redone, ordered from the next one to redo.
+    pub fn redoable(&self) -> impl Iterator<Item=UndoHistoryEntry<'_, E>> {
+        let mut entries = self
+            .registered
+            .redo
+            .iter()
+            .map(UndoHistoryEntry::from)
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.no);
+        entries.into_iter()
+    }
+
+
+    /// Returns the most recently registered entry.
+    #[inline]
+    pub fn latest(&self) -> Option<UndoHistoryEntry<'_, E>> {
+        self.iter().last()
+    }
+
+
+    /// Returns the entries placed on the reserved area that have not been committed yet.
+    pub fn reserved(&self) -> impl Iterator<Item=(&UndoEntryMeta, &E)> {
+        self.reserved.0.iter().map(|e| (&e.meta, &e.inner))
+    }
+
+
+    /// Returns the number of entries that can be undone.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.registered.len()
+    }
+
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+
+impl<'a, E: Event> From<&'a UndoEvent<E>> for UndoHistoryEntry<'a, E> {
+    #[inline]
+    fn from(undo: &'a UndoEvent<E>) -> Self {
+        Self {
+            no: undo.no,
+            meta: &undo.meta,
+            event: &undo.inner,
+            id: undo.id,
+        }
+    }
+}
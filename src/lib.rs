@@ -1,23 +1,57 @@
-use bevy::app::{App, Plugin};
-use bevy::prelude::{Event, IntoSystemConfigs, EventReader, EventWriter, PreUpdate, ResMut, Resource};
+use std::collections::HashMap;
 
-use crate::counter::UndoCounter;
-use crate::request::RequestUndoEvent;
+use bevy::app::{App, Last, Plugin, Update};
+use bevy::ecs::event::{Events, ManualEventReader};
+use bevy::prelude::{Event, EventReader, EventWriter, PreUpdate, ResMut, Resource};
+
+use crate::context::{undo_applying_system, UndoApplying};
+use crate::counter::{UndoCounter, UndoEntryIds};
+use crate::extension::UndoAreas;
+use crate::progress::UndoCompletions;
+use crate::remap::{UndoEntityMap, UndoRemapping};
+use crate::request::UndoRequestQueue;
 use crate::reserve::{RequestCommitReservationsEvent, RequestCommitReservationsFromSchedulerEvent, ReserveCounter};
-use crate::undo_event::UndoEvent;
+use crate::tree::{UndoBranchEvent, UndoTree};
+use crate::undo_event::{RedoEvent, UndoEvent};
+use crate::validate::UndoInvalidEntryEvent;
+use crate::request::request_queue_system;
+use crate::veto::{UndoVetoedEvent, UndoVetoes};
 
+mod asset;
+mod command;
+mod context;
 mod counter;
 mod extension;
+mod history;
+mod progress;
+mod remap;
 mod request;
 mod undo_event;
 mod reserve;
+mod snapshot;
+mod tree;
+mod validate;
+mod veto;
 
 pub mod prelude {
+    pub use crate::asset::UndoAssets;
+    pub use crate::command::{CommandsUndoEx, EntityCommandsUndoEx, UndoableCommand};
+    pub use crate::command::query::UndoQuery;
+    pub use crate::command::reflect::UndoablePropertyEdit;
+    pub use crate::command::resource::UndoResMut;
+    pub use crate::context::{UndoContext, UndoOrigin};
     pub use crate::extension::AppUndoEx;
-    pub use crate::request::{UndoRequester};
-    pub use crate::undo_event::{UndoReserveCommitter, UndoScheduler};
+    pub use crate::history::{UndoAuthor, UndoEntryMeta, UndoHistory, UndoHistoryEntry};
+    pub use crate::progress::{UndoInProgress, UndoQueuePolicy};
+    pub use crate::remap::{UndoEntityMap, UndoMapEntities};
+    pub use crate::request::{UndoDirection, UndoRequester};
+    pub use crate::snapshot::{UndoSnapshotMemoryBudget, UndoSnapshotScheduler};
+    pub use crate::tree::{UndoBranch, UndoTree};
+    pub use crate::undo_event::{UndoHandle, UndoReserveCommitter, UndoScheduler};
+    pub use crate::validate::{UndoInvalidEntryEvent, UndoInvalidEntryPolicy, UndoTargets};
+    pub use crate::veto::{UndoVerdict, UndoVetoedEvent};
     #[cfg(feature = "callback_event")]
-    pub use crate::undo_event::callback::UndoCallbackEvent;
+    pub use crate::undo_event::callback::{UndoCallbackEvent, UndoOnceCallbackEvent, UndoWorldCallbackEvent};
     pub use crate::UndoPlugin;
 }
 
@@ -30,16 +64,30 @@ pub struct UndoPlugin;
 impl Plugin for UndoPlugin {
     fn build(&self, app: &mut App) {
         app
-            .add_event::<RequestUndoEvent>()
+            .add_event::<UndoVetoedEvent>()
+            .add_event::<UndoBranchEvent>()
             .add_event::<CommitReservationsEvent>()
-            .add_event::<DecrementCounterEvent>()
             .add_event::<RequestCommitReservationsFromSchedulerEvent>()
             .add_event::<RequestCommitReservationsEvent>()
+            .add_event::<UndoInvalidEntryEvent>()
             .init_resource::<UndoCounter>()
-            .add_systems(PreUpdate, (
-                decrement_counter,
-                reserve_reset_system
-            ).chain());
+            .init_resource::<UndoEntryIds>()
+            .init_resource::<ReserveCounter>()
+            .init_resource::<UndoTree>()
+            .init_resource::<UndoEntityMap>()
+            .init_resource::<UndoRemapping>()
+            .init_resource::<UndoRequestQueue>()
+            .init_resource::<UndoAreas>()
+            .init_resource::<UndoVetoes>()
+            .init_resource::<UndoCompletions>()
+            .init_resource::<UndoApplying>()
+            .add_systems(Update, request_queue_system)
+            .add_systems(Last, undo_applying_system)
+            .add_systems(PreUpdate, reserve_reset_system);
+
+        app
+            .add_plugins(crate::command::UndoCommandPlugin)
+            .add_plugins(crate::snapshot::UndoSnapshotPlugin);
 
         #[cfg(feature = "callback_event")]
         app.add_plugins(crate::undo_event::callback::UndoCallbackEventPlugin);
@@ -48,67 +96,238 @@ impl Plugin for UndoPlugin {
 
 
 #[derive(Resource)]
-struct UndoRegisteredArea<T: Event + Clone>(Vec<UndoEvent<T>>);
+struct UndoRegisteredArea<T: Event> {
+    undo: Vec<UndoEvent<T>>,
+    redo: Vec<UndoEvent<T>>,
+    branches: HashMap<usize, Vec<UndoEvent<T>>>,
+
+    /// Reads the [`UndoBranchEvent`]s which have not been applied to this area yet.
+    branch_reader: ManualEventReader<UndoBranchEvent>,
+}
 
 
-impl<T: Event + Clone> Default for UndoRegisteredArea<T> {
+impl<T: Event> Default for UndoRegisteredArea<T> {
     #[inline(always)]
     fn default() -> Self {
-        Self(vec![])
+        Self {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            branches: HashMap::new(),
+            branch_reader: ManualEventReader::default(),
+        }
     }
 }
 
 
-impl<E: Event + Clone> UndoRegisteredArea<E> {
-    #[inline(always)]
+impl<E: Event> UndoRegisteredArea<E> {
+    /// Pushes the entry keeping the entries ordered by their step.
+    #[inline]
     pub fn push(&mut self, e: UndoEvent<E>) {
-        self.0.push(e);
+        let index = self.undo.partition_point(|undo| undo.no <= e.no);
+        self.undo.insert(index, e);
     }
 
 
-    #[inline(always)]
-    pub fn pop(&mut self) -> Option<E> {
-        self.0.pop().map(|e| e.inner)
+    /// Returns the latest entry if it belongs to the step `no`.
+    #[inline]
+    pub fn last_mut(&mut self, no: usize) -> Option<&mut UndoEvent<E>> {
+        self.undo.last_mut().filter(|undo| undo.no == no)
     }
 
 
+    /// Returns the number of entries that can be undone.
     #[inline(always)]
-    pub fn pop_if_has_latest(&mut self, counter: &UndoCounter) -> Option<E> {
-        let index = self.0.iter().position(|undo| {
-            **counter <= undo.no
-        })?;
+    pub fn len(&self) -> usize {
+        self.undo.len()
+    }
+
 
-        Some(self.0.remove(index).inner)
+    /// Returns the events of all entries kept in the history, including the undone ones and the stashed branches.
+    pub fn events(&self) -> impl Iterator<Item=&E> {
+        self.undo
+            .iter()
+            .chain(self.redo.iter())
+            .chain(self.branches.values().flatten())
+            .map(|undo| &undo.inner)
     }
-}
 
 
-#[derive(Event)]
-pub(crate) struct DecrementCounterEvent;
+    /// Returns the undo-events and redo-events of all entries kept in the history,
+    /// including the undone ones and the stashed branches.
+    pub fn events_mut(&mut self) -> impl Iterator<Item=&mut E> {
+        self.undo
+            .iter_mut()
+            .chain(self.redo.iter_mut())
+            .chain(self.branches.values_mut().flatten())
+            .flat_map(|undo| std::iter::once(&mut undo.inner).chain(undo.redo.as_mut().map(|redo| &mut redo.inner)))
+    }
 
 
-fn decrement_counter(
-    mut er: EventReader<DecrementCounterEvent>,
-    mut counter: ResMut<UndoCounter>,
-) {
-    for _ in er.iter() {
-        counter.decrement();
+    /// Drops the entries, including the undone ones and the stashed branches, for which `f` returns false.
+    pub fn retain(&mut self, mut f: impl FnMut(&E) -> bool) {
+        self.undo.retain(|undo| f(&undo.inner));
+        self.redo.retain(|undo| f(&undo.inner));
+        for entries in self.branches.values_mut() {
+            entries.retain(|undo| f(&undo.inner));
+        }
+    }
+
+
+    /// Returns the events of the step `no`, or its redo-events from the redo area if `redo` is true.
+    pub fn step_events(&self, no: usize, redo: bool) -> impl Iterator<Item=&E> {
+        let entries = if redo { &self.redo } else { &self.undo };
+        entries
+            .iter()
+            .filter(move |undo| undo.no == no)
+            .map(move |undo| match (&undo.redo, redo) {
+                (Some(redo), true) => &redo.inner,
+                _ => &undo.inner
+            })
+    }
+
+
+    /// Returns true if the step `no` is undone and redone together with the previous one.
+    #[inline]
+    pub fn is_chained(&self, no: usize) -> bool {
+        self.undo.iter().chain(self.redo.iter()).any(|undo| undo.no == no && undo.chained)
+    }
+
+
+    /// Returns true if the step `no` has entries in the redo area.
+    #[inline]
+    pub fn has_redo(&self, no: usize) -> bool {
+        self.redo.iter().any(|undo| undo.no == no)
+    }
+
+
+    /// Drops the entries of the step `no` for which `f` returns false.
+    ///
+    /// If `redo` is true, the entries are taken from the redo area and `f` is called with their redo-events.
+    pub fn retain_step(&mut self, no: usize, redo: bool, mut f: impl FnMut(&E) -> bool) {
+        if redo {
+            self.redo.retain(|undo| undo.no != no || f(undo.redo.as_ref().map_or(&undo.inner, |redo| &redo.inner)));
+        } else {
+            self.undo.retain(|undo| undo.no != no || f(&undo.inner));
+        }
+    }
+
+
+    /// Moves the entries of the step `no` that have no redo-event and for which `f` returns true to the redo area,
+    /// so that they are not moved out of the history when the step is undone.
+    pub fn keep_for_redo(&mut self, no: usize, mut f: impl FnMut(&E) -> bool) {
+        let (kept, undo) = std::mem::take(&mut self.undo)
+            .into_iter()
+            .partition::<Vec<_>, _>(|undo| undo.no == no && undo.redo.is_none() && f(&undo.inner));
+        self.undo = undo;
+        self.redo.extend(kept);
     }
+
+
+    /// Removes the entries of the step `no` and returns the events to send, latest first.
+    ///
+    /// Entries registered with a redo-event are moved to the redo area and their undo-event is cloned,
+    /// the others are moved out of the history.
+    pub fn undo(&mut self, no: usize) -> Vec<E> {
+        let mut events = Vec::new();
+        let mut redo = Vec::new();
+        for undo in take_step(&mut self.undo, no).into_iter().rev() {
+            match &undo.redo {
+                Some(RedoEvent { clone, .. }) => {
+                    events.push(clone(&undo.inner));
+                    redo.push(undo);
+                }
+                None => events.push(undo.inner)
+            }
+        }
+        self.redo.extend(redo.into_iter().rev());
+        events
+    }
+
+
+    /// Returns the step of the entry with the id, if it can be undone.
+    pub fn find_entry(&self, id: usize) -> Option<usize> {
+        self.undo.iter().find(|undo| undo.id == id).map(|undo| undo.no)
+    }
+
+
+    /// Removes the entries of the step `no` without moving them to the redo area,
+    /// and returns the events to send, latest first.
+    pub fn undo_entry(&mut self, no: usize) -> Vec<E> {
+        take_step(&mut self.undo, no)
+            .into_iter()
+            .rev()
+            .map(|undo| undo.inner)
+            .collect()
+    }
+
+
+    /// Moves the entries of the step `no` back from the redo area and returns their redo-events, oldest first.
+    pub fn redo(&mut self, no: usize) -> Vec<E> {
+        let entries = take_step(&mut self.redo, no);
+        let events = entries
+            .iter()
+            .filter_map(|undo| undo.redo.as_ref())
+            .map(|redo| (redo.clone)(&redo.inner))
+            .collect();
+        for undo in entries {
+            self.push(undo);
+        }
+        events
+    }
+
+
+    /// Applies the branch events sent since the last call, so that the redo area matches the [`UndoTree`].
+    pub fn sync_branches(&mut self, events: &Events<UndoBranchEvent>) {
+        let branches = self.branch_reader.iter(events).copied().collect::<Vec<_>>();
+        for branch in branches {
+            self.branch(branch);
+        }
+    }
+
+
+    fn branch(&mut self, UndoBranchEvent { fork, stash, restore }: UndoBranchEvent) {
+        let (forward, redo) = std::mem::take(&mut self.redo)
+            .into_iter()
+            .partition::<Vec<_>, _>(|undo| fork < undo.no);
+        self.redo = redo;
+
+        if let Some(stash) = stash.filter(|_| !forward.is_empty()) {
+            self.branches.insert(stash, forward);
+        }
+        if let Some(entries) = restore.and_then(|restore| self.branches.remove(&restore)) {
+            self.redo.extend(entries);
+        }
+    }
+}
+
+
+fn take_step<E: Event>(entries: &mut Vec<UndoEvent<E>>, no: usize) -> Vec<UndoEvent<E>> {
+    let (step, rest) = std::mem::take(entries)
+        .into_iter()
+        .partition(|undo| undo.no == no);
+    *entries = rest;
+    step
 }
 
 
+/// Commits the reservations as one step each, on top of the step held in this event.
 #[derive(Event)]
-pub(crate) struct CommitReservationsEvent(pub UndoCounter);
+pub(crate) struct CommitReservationsEvent(pub usize);
 
 fn reserve_reset_system(
     mut er: EventReader<RequestCommitReservationsEvent>,
     mut er2: EventReader<RequestCommitReservationsFromSchedulerEvent>,
     mut ew: EventWriter<CommitReservationsEvent>,
+    mut branch_writer: EventWriter<UndoBranchEvent>,
     mut counter: ResMut<UndoCounter>,
     mut reserve_counter: ResMut<ReserveCounter>,
+    mut tree: ResMut<UndoTree>,
 ) {
-    if er.iter().next().is_some() || er2.iter().next().is_some() {
-        ew.send(CommitReservationsEvent(*counter));
+    if (er.iter().next().is_some() || er2.iter().next().is_some()) && 0 < **reserve_counter {
+        if let Some(event) = tree.advance(&counter, **reserve_counter) {
+            branch_writer.send(event);
+        }
+        ew.send(CommitReservationsEvent(**counter));
         *counter += *reserve_counter;
         reserve_counter.reset();
     }
@@ -117,14 +336,34 @@ fn reserve_reset_system(
 
 #[cfg(test)]
 mod tests {
-    use bevy::app::{App, Startup, Update};
+    use std::any::TypeId;
+
+    use bevy::app::{App, PostUpdate, Startup, Update};
+    use bevy::asset::{AddAsset, AssetPlugin, Assets};
+    use bevy::reflect::{TypePath, TypeUuid};
+    use bevy::ecs::system::SystemState;
     use bevy::input::Input;
-    use bevy::prelude::{Commands, Component, Event, EventReader, KeyCode, Res};
+    use bevy::hierarchy::{BuildWorldChildren, Children, Parent};
+    use bevy::prelude::{BuildChildren, Commands, Component, Entity, Event, EventReader, IntoSystemConfigs, KeyCode, Local, Query, Reflect, ReflectComponent, Res, ResMut, Resource, With, World};
+    use crate::asset::UndoAssets;
+    use crate::command::{CommandsUndoEx, EntityCommandsUndoEx, UndoableCommand};
+    use crate::command::query::UndoQuery;
+    use crate::command::resource::UndoResMut;
+    use crate::context::{UndoContext, UndoOrigin};
     use crate::counter::UndoCounter;
     use crate::extension::AppUndoEx;
+    use crate::history::{UndoAuthor, UndoHistory};
     use crate::prelude::UndoRequester;
-    use crate::reserve::{ReserveCounter, UndoReservedArea, UndoReserveEvent};
-    use crate::undo_event::UndoScheduler;
+    use crate::progress::{UndoInProgress, UndoQueuePolicy};
+    use crate::request::{UndoDirection, UndoRequestQueue};
+    use crate::remap::{UndoEntityMap, UndoMapEntities};
+    use crate::reserve::{ReserveCounter, UndoReservedArea};
+    use crate::snapshot::{UndoSnapshotMemoryBudget, UndoSnapshotEvent, UndoSnapshotScheduler, UndoSnapshotStore};
+    use crate::tree::UndoTree;
+    use crate::undo_event::{UndoHandle, UndoScheduler};
+    use crate::validate::{UndoInvalidEntryEvent, UndoInvalidEntryPolicy, UndoTargets};
+    use crate::request::request_queue_system;
+    use crate::veto::{UndoVerdict, UndoVetoedEvent};
     use crate::{UndoPlugin, UndoRegisteredArea};
 
     #[derive(Event, Clone, Default)]
@@ -133,6 +372,41 @@ mod tests {
     #[derive(Component)]
     struct OnUndo;
 
+    #[derive(Resource)]
+    struct Handle(UndoHandle);
+
+    #[derive(Event, Component, Clone, PartialEq, Debug)]
+    struct Value(usize);
+
+    #[derive(Component, Reflect, Default, PartialEq, Debug)]
+    #[reflect(Component)]
+    struct Marker(usize);
+
+    #[derive(TypeUuid, TypePath, Clone, PartialEq, Debug)]
+    #[uuid = "4a7bd6c2-51f4-4c4b-9f41-2d0a8f6b3e10"]
+    struct Material(usize);
+
+    #[derive(Resource, Default)]
+    struct Vetoed(Vec<UndoVetoedEvent>);
+
+    #[derive(Event)]
+    struct Target(Entity);
+
+    impl UndoTargets for Target {
+        fn targets(&self) -> Vec<Entity> {
+            vec![self.0]
+        }
+    }
+
+    impl UndoMapEntities for Target {
+        fn map_entities(&mut self, map: &UndoEntityMap) {
+            map.map(&mut self.0);
+        }
+    }
+
+    #[derive(Resource, Default)]
+    struct Targeted(Vec<Entity>, Vec<UndoInvalidEntryEvent>);
+
 
     #[test]
     fn once_register() {
@@ -191,6 +465,33 @@ mod tests {
     }
 
 
+    #[test]
+    fn reservations_are_separate_steps_undone_together() {
+        let mut app = new_app();
+        app.add_systems(Startup, |mut s: UndoScheduler<UndoEvent>| {
+            s.reserve_with_redo(UndoEvent, UndoEvent);
+            s.reserve_with_redo(UndoEvent, UndoEvent);
+            s.reserve_with_redo(UndoEvent, UndoEvent);
+            s.register_all_reserved();
+        });
+        app.update();
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 3);
+        assert_eq!(app.world.resource::<UndoRegisteredArea<UndoEvent>>().undo.iter().map(|undo| undo.no).collect::<Vec<_>>(), [1, 2, 3]);
+
+        let mut state = SystemState::<UndoRequester>::new(&mut app.world);
+        state.get_mut(&mut app.world).undo();
+        state.apply(&mut app.world);
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+
+        state.get_mut(&mut app.world).redo();
+        state.apply(&mut app.world);
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 3);
+    }
+
+
     #[test]
     fn reserve_at_intervals() {
         let mut app = new_app();
@@ -221,7 +522,7 @@ mod tests {
         app.update();
 
         assert_eq!(app.world.resource_mut::<UndoReservedArea<UndoEvent>>().0.len(), 0);
-        assert_eq!(app.world.resource_mut::<UndoRegisteredArea<UndoReserveEvent<UndoEvent>>>().0.len(), 3);
+        assert_eq!(app.world.resource_mut::<UndoRegisteredArea<UndoEvent>>().len(), 3);
 
         app.world.resource_mut::<Input<KeyCode>>().reset(KeyCode::B);
         app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::R);
@@ -231,15 +532,1201 @@ mod tests {
 
         assert_eq!(**app.world.resource_mut::<UndoCounter>(), 0);
         assert_eq!(**app.world.resource_mut::<ReserveCounter>(), 0);
-        assert_eq!(app.world.resource_mut::<UndoRegisteredArea<UndoEvent>>().0.len(), 0);
+        assert_eq!(app.world.resource_mut::<UndoRegisteredArea<UndoEvent>>().len(), 0);
         assert_eq!(app.world.resource_mut::<UndoReservedArea<UndoEvent>>().0.len(), 0);
         assert_eq!(app.world.query::<&OnUndo>().iter(&app.world).len(), 3);
     }
 
 
-    fn undo(mut req: UndoRequester, key: Res<Input<KeyCode>>) {
-        if key.just_pressed(KeyCode::R) {
-            req.undo();
+    #[test]
+    fn entries_record_author_and_reserved_meta() {
+        let mut app = new_app();
+        app.insert_resource(UndoAuthor::new("elm"));
+        app.add_systems(Startup, |mut s: UndoScheduler<UndoEvent>| {
+            s.register_default();
+            s.reserve_default();
+            s.register_all_reserved();
+        });
+        app.update();
+        app.update();
+
+        let mut state = SystemState::<UndoHistory<UndoEvent>>::new(&mut app.world);
+        let history = state.get(&app.world);
+        assert_eq!(history.len(), 2);
+        assert!(history.iter().all(|entry| entry.meta.author == Some(UndoAuthor::new("elm"))));
+        assert_eq!(history.latest().map(|entry| entry.no), Some(2));
+    }
+
+
+    #[test]
+    fn redo_after_undo() {
+        let mut app = new_app();
+        app.add_systems(Startup, |mut s: UndoScheduler<UndoEvent>| {
+            s.register_with_redo(UndoEvent, UndoEvent);
+        });
+        app.update();
+
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::R);
+        app.update();
+        app.world.resource_mut::<Input<KeyCode>>().reset(KeyCode::R);
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+        assert_eq!(app.world.query::<&OnUndo>().iter(&app.world).len(), 1);
+
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::T);
+        app.update();
+        app.world.resource_mut::<Input<KeyCode>>().reset(KeyCode::T);
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 1);
+        assert_eq!(app.world.query::<&OnUndo>().iter(&app.world).len(), 2);
+        assert_eq!(app.world.resource::<UndoRegisteredArea<UndoEvent>>().len(), 1);
+    }
+
+
+    #[test]
+    fn redo_without_redo_events_is_rejected() {
+        let mut app = new_app();
+        app
+            .init_resource::<Vetoed>()
+            .add_systems(Update, |mut er: EventReader<UndoVetoedEvent>, mut vetoed: ResMut<Vetoed>| {
+                vetoed.0.extend(er.iter().cloned());
+            });
+        register(&mut app);
+        register(&mut app);
+
+        let mut state = SystemState::<UndoRequester>::new(&mut app.world);
+        let mut requester = state.get_mut(&mut app.world);
+        requester.undo();
+        requester.redo();
+        requester.undo();
+        state.apply(&mut app.world);
+        app.update();
+        app.update();
+
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+        assert_eq!(app.world.query::<&OnUndo>().iter(&app.world).len(), 2);
+        let vetoed = &app.world.resource::<Vetoed>().0;
+        assert_eq!(vetoed.len(), 1);
+        assert_eq!(vetoed[0].no, 2);
+        assert_eq!(vetoed[0].direction, UndoDirection::Redo);
+    }
+
+
+    #[test]
+    fn register_after_undo_creates_branch() {
+        let mut app = new_app();
+        app.enable_undo_tree();
+        app.add_systems(Update, |mut s: UndoScheduler<UndoEvent>, key: Res<Input<KeyCode>>| {
+            if key.just_pressed(KeyCode::A) {
+                s.register_with_redo(UndoEvent, UndoEvent);
+            }
+        });
+
+        for key in [KeyCode::A, KeyCode::A, KeyCode::R, KeyCode::A] {
+            app.world.resource_mut::<Input<KeyCode>>().press(key);
+            app.update();
+            app.world.resource_mut::<Input<KeyCode>>().reset(key);
+            app.update();
+        }
+
+        let tree = app.world.resource::<UndoTree>();
+        assert_eq!(tree.head(), 2);
+        assert_eq!(tree.branches().len(), 1);
+        assert_eq!(tree.branches()[0].fork, 1);
+        assert_eq!(tree.branches()[0].len, 1);
+        let id = tree.branches()[0].id;
+
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::R);
+        app.update();
+        app.world.resource_mut::<Input<KeyCode>>().reset(KeyCode::R);
+        app.update();
+        app.add_systems(Update, move |mut req: UndoRequester, key: Res<Input<KeyCode>>| {
+            if key.just_pressed(KeyCode::S) {
+                req.switch_branch(id);
+            }
+        });
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::S);
+        app.update();
+        app.world.resource_mut::<Input<KeyCode>>().reset(KeyCode::S);
+        app.update();
+
+        let tree = app.world.resource::<UndoTree>();
+        assert_eq!(tree.head(), 2);
+        assert_eq!(tree.branches().len(), 1);
+        assert_ne!(tree.branches()[0].id, id);
+        let area = app.world.resource::<UndoRegisteredArea<UndoEvent>>();
+        assert_eq!(area.redo.len(), 1);
+        assert!(area.branches.values().all(|entries| entries.len() == 1));
+    }
+
+
+    #[test]
+    fn goto_undoes_and_redoes_steps_in_order() {
+        let mut app = new_app();
+        app.add_systems(Startup, |mut s: UndoScheduler<UndoEvent>| {
+            s.register_with_redo(UndoEvent, UndoEvent);
+            s.register_with_redo(UndoEvent, UndoEvent);
+            s.register_with_redo(UndoEvent, UndoEvent);
+        });
+        app.update();
+
+        goto(&mut app, 0);
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+        assert_eq!(app.world.query::<&OnUndo>().iter(&app.world).len(), 3);
+
+        goto(&mut app, 2);
+        assert_eq!(**app.world.resource::<UndoCounter>(), 2);
+        assert_eq!(app.world.query::<&OnUndo>().iter(&app.world).len(), 5);
+        assert_eq!(app.world.resource::<UndoRegisteredArea<UndoEvent>>().len(), 2);
+
+        goto(&mut app, 10);
+        assert_eq!(**app.world.resource::<UndoCounter>(), 3);
+    }
+
+
+    #[test]
+    fn undo_entry_leaves_later_entries() {
+        let mut app = new_app();
+        app.add_systems(Startup, |mut commands: Commands, mut s: UndoScheduler<UndoEvent>| {
+            commands.insert_resource(Handle(s.register_default()));
+            s.register_default();
+        });
+        app.add_systems(Update, |mut req: UndoRequester, handle: Res<Handle>, key: Res<Input<KeyCode>>| {
+            if key.just_pressed(KeyCode::E) {
+                req.undo_entry(handle.0);
+            }
+        });
+        app.update();
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::E);
+        app.update();
+        app.world.resource_mut::<Input<KeyCode>>().reset(KeyCode::E);
+        app.update();
+
+        assert_eq!(app.world.query::<&OnUndo>().iter(&app.world).len(), 1);
+        assert_eq!(**app.world.resource::<UndoCounter>(), 2);
+        let area = app.world.resource::<UndoRegisteredArea<UndoEvent>>();
+        assert_eq!(area.len(), 1);
+        assert_eq!(area.undo[0].no, 2);
+    }
+
+
+    #[test]
+    fn stale_handle_does_not_undo_later_entry() {
+        let mut app = new_app();
+        let stale = register(&mut app);
+        goto(&mut app, 0);
+        assert_eq!(app.world.query::<&OnUndo>().iter(&app.world).len(), 1);
+
+        let handle = register(&mut app);
+        assert_eq!(handle.no(), stale.no());
+        assert_ne!(handle, stale);
+
+        let mut state = SystemState::<UndoRequester>::new(&mut app.world);
+        state.get_mut(&mut app.world).undo_entry(stale);
+        state.apply(&mut app.world);
+        app.update();
+        app.update();
+        assert_eq!(app.world.query::<&OnUndo>().iter(&app.world).len(), 1);
+        assert_eq!(app.world.resource::<UndoRegisteredArea<UndoEvent>>().len(), 1);
+        assert!(app.world.resource::<UndoRequestQueue>().0.is_empty());
+    }
+
+
+    #[test]
+    fn amend_last_modifies_latest_entry() {
+        let mut app = new_app();
+        app.add_undo_event::<Value>();
+        app.add_systems(Startup, |mut s: UndoScheduler<Value>| {
+            s.register_with_redo(Value(1), Value(2));
+            assert!(s.amend_last(|value| value.0 = 10));
+            assert!(s.amend_last_redo(|value| value.0 = 20));
+        });
+        app.add_systems(Update, |mut s: UndoScheduler<UndoEvent>, key: Res<Input<KeyCode>>| {
+            if key.just_pressed(KeyCode::A) {
+                s.register_default();
+            }
+        });
+        app.update();
+
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::A);
+        app.add_systems(PostUpdate, |mut s: UndoScheduler<Value>| {
+            assert!(!s.amend_last(|_| unreachable!()));
+        });
+        app.update();
+
+        let area = app.world.resource::<UndoRegisteredArea<Value>>();
+        assert_eq!(area.undo[0].inner, Value(10));
+        assert_eq!(area.undo[0].redo.as_ref().map(|redo| &redo.inner), Some(&Value(20)));
+    }
+
+
+    #[test]
+    fn undo_event_without_clone() {
+        #[derive(Event)]
+        struct Buffer(Vec<u8>);
+
+        let mut app = new_app();
+        app.add_undo_event::<Buffer>();
+        app.add_systems(Startup, |mut s: UndoScheduler<Buffer>| {
+            s.register(Buffer(vec![1, 2, 3]));
+        });
+        app.add_systems(Update, |mut commands: Commands, mut er: EventReader<Buffer>| {
+            for Buffer(buffer) in er.iter() {
+                assert_eq!(buffer, &[1, 2, 3]);
+                commands.spawn(OnUndo);
+            }
+        });
+        app.update();
+
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::R);
+        app.update();
+        app.update();
+
+        assert_eq!(app.world.query::<&OnUndo>().iter(&app.world).len(), 1);
+        assert_eq!(app.world.resource::<UndoRegisteredArea<Buffer>>().len(), 0);
+    }
+
+
+    #[cfg(feature = "callback_event")]
+    #[test]
+    fn world_callback_reads_world_on_undo() {
+        use crate::undo_event::callback::UndoWorldCallbackEvent;
+
+        #[derive(Resource, Default)]
+        struct Total(usize);
+
+        let mut app = new_app();
+        app.init_resource::<Total>();
+        let entity = app.world.spawn(Value(3)).id();
+        let mut state = SystemState::<UndoScheduler<UndoWorldCallbackEvent>>::new(&mut app.world);
+        state.get_mut(&mut app.world).register(UndoWorldCallbackEvent::new(move |world| {
+            let value = world.get::<Value>(entity).map_or(0, |value| value.0);
+            world.resource_mut::<Total>().0 += value;
+        }));
+        state.apply(&mut app.world);
+
+        goto(&mut app, 0);
+        assert_eq!(app.world.resource::<Total>().0, 3);
+    }
+
+
+    #[cfg(feature = "callback_event")]
+    #[test]
+    fn once_callback_moves_its_capture_out_once() {
+        use std::cell::Cell;
+        use crate::undo_event::callback::{undo_once_callback_event_system, UndoOnceCallbackEvent};
+
+        /// Neither `Clone` nor `Sync`.
+        struct Owned(Cell<usize>);
+
+        #[derive(Resource, Default)]
+        struct Taken(Vec<usize>);
+
+        #[derive(Resource, Default)]
+        struct Missed(usize);
+
+        let mut app = new_app();
+        app
+            .init_resource::<Taken>()
+            .init_resource::<Missed>()
+            .add_systems(Update, (|mut er: EventReader<UndoOnceCallbackEvent>, mut missed: ResMut<Missed>| {
+                missed.0 += er.iter().filter_map(UndoOnceCallbackEvent::take).count();
+            }).after(undo_once_callback_event_system));
+        let owned = Owned(Cell::new(3));
+        let mut state = SystemState::<UndoScheduler<UndoOnceCallbackEvent>>::new(&mut app.world);
+        state.get_mut(&mut app.world).register(UndoOnceCallbackEvent::new(move |commands| {
+            let Owned(value) = owned;
+            let value = value.into_inner();
+            commands.add(move |world: &mut World| world.resource_mut::<Taken>().0.push(value));
+        }));
+        state.apply(&mut app.world);
+
+        goto(&mut app, 0);
+        app.update();
+        assert_eq!(app.world.resource::<Taken>().0, [3]);
+        assert_eq!(app.world.resource::<Missed>().0, 0);
+    }
+
+
+    #[test]
+    fn undoable_command_is_reverted_and_reapplied() {
+        #[derive(Resource, Default)]
+        struct Count(usize);
+
+        struct Increment;
+
+        impl UndoableCommand for Increment {
+            fn apply(&mut self, world: &mut World) {
+                world.resource_mut::<Count>().0 += 1;
+            }
+
+            fn revert(&mut self, world: &mut World) {
+                world.resource_mut::<Count>().0 -= 1;
+            }
+        }
+
+        let mut app = new_app();
+        app.init_resource::<Count>();
+        app.add_systems(Startup, |mut commands: Commands| {
+            commands.add_undoable(Increment);
+        });
+        app.update();
+        assert_eq!(app.world.resource::<Count>().0, 1);
+
+        goto(&mut app, 0);
+        assert_eq!(app.world.resource::<Count>().0, 0);
+
+        goto(&mut app, 1);
+        assert_eq!(app.world.resource::<Count>().0, 1);
+    }
+
+
+    #[test]
+    fn despawn_undoable_restores_entity_in_hierarchy() {
+        let mut app = new_app();
+        app.register_type::<Marker>();
+        let parent = app.world.spawn_empty().id();
+        let first = app.world.spawn(Marker(1)).set_parent(parent).id();
+        let second = app.world.spawn(Marker(2)).set_parent(parent).id();
+        app.add_systems(Startup, move |mut commands: Commands| {
+            commands.entity(first).despawn_undoable();
+            commands.spawn_undoable(Marker(3)).set_parent(parent);
+        });
+        app.update();
+        assert!(app.world.get_entity(first).is_none());
+        assert_eq!(app.world.get::<Children>(parent).unwrap().len(), 2);
+
+        goto(&mut app, 1);
+        assert_eq!(app.world.get::<Children>(parent).unwrap().to_vec(), [second]);
+
+        goto(&mut app, 0);
+        let children = app.world.get::<Children>(parent).unwrap().to_vec();
+        assert_eq!(children.len(), 2);
+        assert_eq!(children[1], second);
+        assert_eq!(app.world.get::<Marker>(children[0]), Some(&Marker(1)));
+
+        goto(&mut app, 2);
+        assert_eq!(app.world.query::<&Marker>().iter(&app.world).len(), 2);
+    }
+
+
+    #[test]
+    fn hierarchy_changes_restore_order_of_children() {
+        let mut app = new_app();
+        let parent = app.world.spawn_empty().id();
+        let other = app.world.spawn_empty().id();
+        let children = [(); 3].map(|_| app.world.spawn_empty().set_parent(parent).id());
+        let orphan = app.world.spawn_empty().id();
+        app.add_systems(Startup, move |mut commands: Commands| {
+            commands.entity(other).push_children_undoable(&[children[1], orphan]);
+            commands.entity(children[0]).remove_parent_undoable();
+            commands.entity(children[2]).set_parent_undoable(other);
+        });
+        app.update();
+        assert_eq!(app.world.get::<Children>(other).unwrap().to_vec(), [children[1], orphan, children[2]]);
+        assert!(app.world.get::<Children>(parent).is_none());
+
+        goto(&mut app, 0);
+        assert_eq!(app.world.get::<Children>(parent).unwrap().to_vec(), children);
+        assert!(app.world.get::<Children>(other).is_none());
+        assert!(app.world.get::<Parent>(orphan).is_none());
+
+        goto(&mut app, 3);
+        assert_eq!(app.world.get::<Children>(other).unwrap().to_vec(), [children[1], orphan, children[2]]);
+    }
+
+
+    #[test]
+    fn despawn_recursive_undoable_restores_subtree() {
+        let mut app = new_app();
+        app.register_type::<Marker>();
+        let parent = app.world.spawn_empty().id();
+        let root = app.world.spawn(Marker(0)).set_parent(parent).id();
+        let sibling = app.world.spawn_empty().set_parent(parent).id();
+        let first = app.world.spawn(Marker(1)).set_parent(root).id();
+        app.world.spawn(Marker(2)).set_parent(root);
+        app.world.spawn(Marker(3)).set_parent(first);
+        app.add_systems(Startup, move |mut commands: Commands| {
+            commands.entity(root).despawn_recursive_undoable();
+        });
+        app.update();
+        assert_eq!(app.world.query::<&Marker>().iter(&app.world).len(), 0);
+
+        goto(&mut app, 0);
+        let siblings = app.world.get::<Children>(parent).unwrap().to_vec();
+        assert_eq!(siblings[1], sibling);
+        assert_eq!(app.world.get::<Marker>(siblings[0]), Some(&Marker(0)));
+        let children = app.world.get::<Children>(siblings[0]).unwrap().to_vec();
+        let markers = children.iter().map(|child| app.world.get::<Marker>(*child)).collect::<Vec<_>>();
+        assert_eq!(markers, [Some(&Marker(1)), Some(&Marker(2))]);
+        let grandchildren = app.world.get::<Children>(children[0]).unwrap().to_vec();
+        assert_eq!(app.world.get::<Marker>(grandchildren[0]), Some(&Marker(3)));
+
+        goto(&mut app, 1);
+        assert_eq!(app.world.query::<&Marker>().iter(&app.world).len(), 0);
+        assert_eq!(app.world.get::<Children>(parent).unwrap().to_vec(), [sibling]);
+    }
+
+
+    #[test]
+    fn asset_edits_are_restored_by_handle() {
+        #[derive(Resource)]
+        struct Edited(bevy::asset::Handle<Material>, bevy::asset::Handle<Material>);
+
+        let mut app = new_app();
+        app
+            .add_plugins(AssetPlugin::default())
+            .add_asset::<Material>()
+            .add_undo_asset::<Material>();
+        let edited = app.world.resource_mut::<Assets<Material>>().add(Material(1));
+        app.add_systems(Startup, move |mut commands: Commands, mut materials: UndoAssets<Material>| {
+            materials.get_mut(&edited).unwrap().0 = 10;
+            let added = materials.add(Material(2));
+            commands.insert_resource(Edited(edited.clone(), added));
+        });
+        app.update();
+        let Edited(edited, added) = app.world.remove_resource::<Edited>().unwrap();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 1);
+
+        goto(&mut app, 0);
+        let materials = app.world.resource::<Assets<Material>>();
+        assert_eq!(materials.get(&edited), Some(&Material(1)));
+        assert_eq!(materials.get(&added), None);
+
+        goto(&mut app, 1);
+        let materials = app.world.resource::<Assets<Material>>();
+        assert_eq!(materials.get(&edited), Some(&Material(10)));
+        assert_eq!(materials.get(&added), Some(&Material(2)));
+    }
+
+
+    #[test]
+    fn asset_accessed_without_write_is_not_registered() {
+        let mut app = new_app();
+        app
+            .add_plugins(AssetPlugin::default())
+            .add_asset::<Material>()
+            .add_undo_asset::<Material>()
+            .add_systems(Startup, |mut scheduler: UndoScheduler<UndoEvent>| {
+                scheduler.register_with_redo(UndoEvent, UndoEvent);
+            });
+        let material = app.world.resource_mut::<Assets<Material>>().add(Material(1));
+        app.update();
+        goto(&mut app, 0);
+
+        app.add_systems(Update, move |mut materials: UndoAssets<Material>| {
+            assert_eq!(materials.get_mut(&material).unwrap().0, 1);
+        });
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+        assert!(app.world.resource::<UndoRegisteredArea<UndoEvent>>().has_redo(1));
+    }
+
+
+    #[test]
+    fn stored_events_are_remapped_to_restored_entities() {
+        let mut app = target_app();
+        app.map_undo_entities::<Target>();
+        let entity = register_and_despawn_target(&mut app);
+        assert_eq!(**app.world.resource::<UndoCounter>(), 2);
+
+        goto(&mut app, 1);
+        let restored = app.world.resource::<UndoEntityMap>().get(entity);
+        assert_ne!(restored, entity);
+        assert!(app.world.get_entity(restored).is_some());
+
+        goto(&mut app, 0);
+        assert_eq!(app.world.resource::<Targeted>().0, [restored]);
+    }
+
+
+    #[test]
+    fn steps_after_restoration_in_one_goto_are_remapped() {
+        let mut app = target_app();
+        app.map_undo_entities::<Target>();
+        let entity = register_and_despawn_target(&mut app);
+
+        goto(&mut app, 0);
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+        let restored = app.world.resource::<UndoEntityMap>().get(entity);
+        assert_ne!(restored, entity);
+        assert_eq!(app.world.resource::<Targeted>().0, [restored]);
+    }
+
+
+    #[test]
+    fn undoable_commands_follow_restored_entities() {
+        let mut app = new_app();
+        app.add_systems(Startup, |mut commands: Commands| {
+            commands.spawn_undoable(()).insert_undoable(Value(1));
+        });
+        app.update();
+
+        goto(&mut app, 0);
+        assert_eq!(app.world.query::<&Value>().iter(&app.world).len(), 0);
+
+        goto(&mut app, 2);
+        assert_eq!(app.world.query::<&Value>().iter(&app.world).len(), 1);
+    }
+
+
+    #[test]
+    fn entries_with_missing_targets_are_dropped() {
+        let mut app = target_app();
+        app.validate_undo_targets::<Target>(UndoInvalidEntryPolicy::Drop);
+        let alive = app.world.spawn_empty().id();
+        let despawned = app.world.spawn_empty().id();
+        app.add_systems(Startup, move |mut scheduler: UndoScheduler<Target>| {
+            scheduler.reserve(Target(alive));
+            scheduler.reserve(Target(despawned));
+            scheduler.register_all_reserved();
+        });
+        app.update();
+        app.update();
+        app.world.despawn(despawned);
+
+        goto(&mut app, 0);
+        let targeted = app.world.resource::<Targeted>();
+        assert_eq!(targeted.0, [alive]);
+        assert_eq!(targeted.1.len(), 1);
+        assert_eq!(targeted.1[0].missing, [despawned]);
+        assert_eq!(targeted.1[0].policy, UndoInvalidEntryPolicy::Drop);
+        assert_eq!(app.world.resource::<UndoRegisteredArea<Target>>().events().count(), 0);
+    }
+
+
+    #[test]
+    fn skipped_entries_stay_in_history() {
+        let mut app = target_app();
+        app.validate_undo_targets::<Target>(UndoInvalidEntryPolicy::Skip);
+        let despawned = app.world.spawn_empty().id();
+        let mut state = SystemState::<UndoScheduler<Target>>::new(&mut app.world);
+        state.get_mut(&mut app.world).register(Target(despawned));
+        state.apply(&mut app.world);
+        app.world.despawn(despawned);
+
+        goto(&mut app, 0);
+        let targeted = app.world.resource::<Targeted>();
+        assert!(targeted.0.is_empty());
+        assert_eq!(targeted.1.len(), 1);
+        assert_eq!(targeted.1[0].policy, UndoInvalidEntryPolicy::Skip);
+        assert_eq!(app.world.resource::<UndoRegisteredArea<Target>>().events().count(), 1);
+
+        goto(&mut app, 1);
+        assert_eq!(**app.world.resource::<UndoCounter>(), 1);
+        assert_eq!(app.world.resource::<UndoRegisteredArea<Target>>().len(), 1);
+    }
+
+
+    #[test]
+    fn vetoed_requests_are_postponed_or_rejected() {
+        #[derive(Resource)]
+        struct Modal(bool);
+
+        let mut app = new_app();
+        app
+            .insert_resource(Modal(true))
+            .init_resource::<Vetoed>()
+            .add_undo_veto(|world, _, _| match world.resource::<Modal>().0 {
+                true => UndoVerdict::Postpone("modal".to_string()),
+                false => UndoVerdict::Allow
+            })
+            .add_undo_event_veto::<UndoEvent>(|world, _| match world.contains_resource::<Handle>() {
+                true => UndoVerdict::Reject("busy".to_string()),
+                false => UndoVerdict::Allow
+            })
+            .add_systems(Update, |mut er: EventReader<UndoVetoedEvent>, mut vetoed: ResMut<Vetoed>| {
+                vetoed.0.extend(er.iter().cloned());
+            });
+        register(&mut app);
+
+        goto(&mut app, 0);
+        assert_eq!(**app.world.resource::<UndoCounter>(), 1);
+        assert_eq!(app.world.resource::<Vetoed>().0[0].verdict, UndoVerdict::Postpone("modal".to_string()));
+
+        app.world.resource_mut::<Modal>().0 = false;
+        app.update();
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+
+        let handle = register(&mut app);
+        app.world.insert_resource(Handle(handle));
+        goto(&mut app, 0);
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 1);
+        let vetoed = app.world.resource::<Vetoed>().0.last().cloned().unwrap();
+        assert_eq!(vetoed.direction, UndoDirection::Undo);
+        assert_eq!(vetoed.verdict, UndoVerdict::Reject("busy".to_string()));
+
+        let rejected = app.world.resource::<Vetoed>().0.len();
+        let mut state = SystemState::<UndoRequester>::new(&mut app.world);
+        state.get_mut(&mut app.world).undo_entry(handle);
+        state.apply(&mut app.world);
+        app.update();
+        app.update();
+        assert_eq!(app.world.resource::<UndoRegisteredArea<UndoEvent>>().len(), 1);
+        assert_eq!(app.world.resource::<Vetoed>().0.len(), rejected + 1);
+        assert_eq!(app.world.query::<&OnUndo>().iter(&app.world).len(), 1);
+    }
+
+
+    #[test]
+    fn vetoed_step_holds_its_whole_chain() {
+        let mut app = new_app();
+        app
+            .add_undo_veto(|_, _, no| match no {
+                2 => UndoVerdict::Reject("locked".to_string()),
+                _ => UndoVerdict::Allow
+            })
+            .add_systems(Startup, |mut s: UndoScheduler<UndoEvent>| {
+                s.reserve_with_redo(UndoEvent, UndoEvent);
+                s.reserve_with_redo(UndoEvent, UndoEvent);
+                s.reserve_with_redo(UndoEvent, UndoEvent);
+                s.register_all_reserved();
+            });
+        app.update();
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 3);
+
+        let mut state = SystemState::<UndoRequester>::new(&mut app.world);
+        state.get_mut(&mut app.world).undo();
+        state.apply(&mut app.world);
+        app.update();
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 3);
+        assert_eq!(app.world.resource::<UndoRegisteredArea<UndoEvent>>().len(), 3);
+        assert_eq!(app.world.query::<&OnUndo>().iter(&app.world).len(), 0);
+    }
+
+
+    #[test]
+    fn requests_wait_until_step_is_completed() {
+        let mut app = new_app();
+        app.defer_undo_completion::<UndoEvent>();
+        register(&mut app);
+        register(&mut app);
+
+        goto(&mut app, 0);
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 1);
+        assert_eq!(app.world.resource::<UndoInProgress>().no(), 2);
+
+        app.world.resource_mut::<UndoInProgress>().complete();
+        app.update();
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+        assert_eq!(app.world.resource::<UndoInProgress>().no(), 1);
+
+        app.insert_resource(UndoQueuePolicy::Discard);
+        goto(&mut app, 2);
+        app.world.resource_mut::<UndoInProgress>().complete();
+        app.update();
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+    }
+
+
+    #[test]
+    fn completion_can_be_deferred_before_plugin() {
+        let mut app = App::new();
+        app
+            .defer_undo_completion::<UndoEvent>()
+            .add_plugins(UndoPlugin)
+            .add_undo_event::<UndoEvent>();
+        register(&mut app);
+
+        goto(&mut app, 0);
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+        assert_eq!(app.world.resource::<UndoInProgress>().no(), 1);
+    }
+
+
+    #[test]
+    fn skipped_entries_are_not_awaited() {
+        let mut app = target_app();
+        app
+            .validate_undo_targets::<Target>(UndoInvalidEntryPolicy::Skip)
+            .defer_undo_completion::<Target>();
+        let alive = app.world.spawn_empty().id();
+        let despawned = app.world.spawn_empty().id();
+        let mut state = SystemState::<UndoScheduler<Target>>::new(&mut app.world);
+        let mut scheduler = state.get_mut(&mut app.world);
+        scheduler.register(Target(alive));
+        scheduler.register(Target(despawned));
+        state.apply(&mut app.world);
+        app.world.despawn(despawned);
+
+        goto(&mut app, 0);
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+        assert_eq!(app.world.resource::<UndoInProgress>().no(), 1);
+    }
+
+
+    #[test]
+    fn requests_in_same_frame_undo_consecutive_steps() {
+        let mut app = new_app();
+        register(&mut app);
+        register(&mut app);
+
+        let mut state = SystemState::<UndoRequester>::new(&mut app.world);
+        let mut requester = state.get_mut(&mut app.world);
+        requester.undo();
+        requester.undo();
+        requester.undo();
+        state.apply(&mut app.world);
+        app.update();
+        app.update();
+
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+        assert_eq!(app.world.query::<&OnUndo>().iter(&app.world).len(), 2);
+    }
+
+
+    #[test]
+    fn register_after_undo_in_same_frame_takes_next_step() {
+        let mut app = new_app();
+        app.add_systems(Update, (|mut scheduler: UndoScheduler<UndoEvent>, mut registered: Local<bool>| {
+            if !*registered {
+                scheduler.register(UndoEvent);
+                *registered = true;
+            }
+        }).after(request_queue_system));
+        register(&mut app);
+        register(&mut app);
+
+        let mut state = SystemState::<UndoRequester>::new(&mut app.world);
+        state.get_mut(&mut app.world).undo();
+        state.apply(&mut app.world);
+        app.update();
+
+        assert_eq!(**app.world.resource::<UndoCounter>(), 2);
+        assert_eq!(app.world.resource::<UndoTree>().head(), 2);
+        assert_eq!(app.world.resource::<UndoRegisteredArea<UndoEvent>>().undo.iter().map(|undo| undo.no).collect::<Vec<_>>(), [1, 2]);
+    }
+
+
+    #[test]
+    fn undo_context_tells_origin_of_events() {
+        #[derive(Resource, Default)]
+        struct Origins(Vec<UndoOrigin>);
+
+        let mut app = new_app();
+        app
+            .init_resource::<Origins>()
+            .add_systems(Update, |mut er: EventReader<UndoEvent>, context: Res<UndoContext<UndoEvent>>, mut origins: ResMut<Origins>| {
+                origins.0.extend(er.iter_with_id().map(|(_, id)| context.origin(id)));
+            });
+        let mut state = SystemState::<UndoScheduler<UndoEvent>>::new(&mut app.world);
+        state.get_mut(&mut app.world).register_with_redo(UndoEvent, UndoEvent);
+        state.apply(&mut app.world);
+
+        app.world.send_event(UndoEvent);
+        app.update();
+        goto(&mut app, 0);
+        goto(&mut app, 1);
+
+        let mut state = SystemState::<UndoRequester>::new(&mut app.world);
+        state.get_mut(&mut app.world).undo();
+        state.apply(&mut app.world);
+        app.update();
+        state.get_mut(&mut app.world).redo();
+        state.apply(&mut app.world);
+        app.update();
+        app.update();
+
+        assert_eq!(app.world.resource::<Origins>().0, [
+            UndoOrigin::User,
+            UndoOrigin::Replay,
+            UndoOrigin::Replay,
+            UndoOrigin::Undo,
+            UndoOrigin::Redo,
+        ]);
+    }
+
+
+    fn register(app: &mut App) -> UndoHandle {
+        let mut state = SystemState::<UndoScheduler<UndoEvent>>::new(&mut app.world);
+        let handle = state.get_mut(&mut app.world).register(UndoEvent);
+        state.apply(&mut app.world);
+        handle
+    }
+
+
+    #[test]
+    fn component_changes_are_undoable() {
+        let mut app = new_app();
+        let entity = app.world.spawn_empty().id();
+        app.add_systems(Startup, move |mut commands: Commands| {
+            commands
+                .entity(entity)
+                .insert_undoable(Value(1))
+                .modify_undoable(|value: &mut Value| value.0 = 2)
+                .remove_undoable::<Value>()
+                .remove_undoable::<Value>();
+        });
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 3);
+        assert_eq!(app.world.get::<Value>(entity), None);
+
+        goto(&mut app, 2);
+        assert_eq!(app.world.get::<Value>(entity), Some(&Value(2)));
+
+        goto(&mut app, 1);
+        assert_eq!(app.world.get::<Value>(entity), Some(&Value(1)));
+
+        goto(&mut app, 0);
+        assert_eq!(app.world.get::<Value>(entity), None);
+
+        goto(&mut app, 2);
+        assert_eq!(app.world.get::<Value>(entity), Some(&Value(2)));
+    }
+
+
+    #[test]
+    fn tracked_component_changes_are_recorded() {
+        let mut app = new_app();
+        app.track_undo_component::<Value>();
+        let entity = app.world.spawn(Value(0)).id();
+        app.add_systems(Update, |mut values: Query<&mut Value>, key: Res<Input<KeyCode>>| {
+            if key.just_pressed(KeyCode::A) {
+                values.single_mut().0 += 1;
+            }
+        });
+        app.update();
+
+        for _ in 0..2 {
+            app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::A);
+            app.update();
+            app.world.resource_mut::<Input<KeyCode>>().reset(KeyCode::A);
+            app.update();
+        }
+        assert_eq!(**app.world.resource::<UndoCounter>(), 2);
+
+        goto(&mut app, 0);
+        assert_eq!(app.world.get::<Value>(entity), Some(&Value(0)));
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+        assert_eq!(app.world.resource::<UndoTree>().head(), 2);
+
+        goto(&mut app, 1);
+        assert_eq!(app.world.get::<Value>(entity), Some(&Value(1)));
+
+        #[derive(Event, Clone)]
+        struct Set(usize);
+
+        app
+            .add_undo_event::<Set>()
+            .add_systems(Update, |mut er: EventReader<Set>, mut values: Query<&mut Value>| {
+                for Set(value) in er.iter() {
+                    values.single_mut().0 = *value;
+                }
+            });
+        goto(&mut app, 0);
+        let mut state = SystemState::<UndoScheduler<Set>>::new(&mut app.world);
+        state.get_mut(&mut app.world).register_with_redo(Set(7), Set(0));
+        state.apply(&mut app.world);
+
+        goto(&mut app, 0);
+        app.update();
+        assert_eq!(app.world.get::<Value>(entity), Some(&Value(7)));
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+        assert_eq!(app.world.resource::<UndoTree>().head(), 1);
+
+        goto(&mut app, 1);
+        assert_eq!(app.world.get::<Value>(entity), Some(&Value(0)));
+    }
+
+
+    #[test]
+    fn undo_res_mut_registers_changes() {
+        #[derive(Resource, Clone)]
+        struct GridSize(usize);
+
+        let mut app = new_app();
+        app.insert_resource(GridSize(8));
+        app.add_systems(Update, |mut grid: UndoResMut<GridSize>, key: Res<Input<KeyCode>>| {
+            if key.just_pressed(KeyCode::A) {
+                grid.0 *= 2;
+                grid.0 += 1;
+            }
+            assert!(0 < grid.0);
+        });
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::A);
+        app.update();
+        assert_eq!(app.world.resource::<GridSize>().0, 17);
+        assert_eq!(**app.world.resource::<UndoCounter>(), 1);
+
+        app.world.resource_mut::<Input<KeyCode>>().reset(KeyCode::A);
+        goto(&mut app, 0);
+        assert_eq!(app.world.resource::<GridSize>().0, 8);
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+    }
+
+
+    #[test]
+    fn undo_query_registers_changed_entities_as_one_step() {
+        let mut app = new_app();
+        let first = app.world.spawn(Value(1)).id();
+        let second = app.world.spawn(Value(2)).id();
+        app.add_systems(Update, |mut values: UndoQuery<Value>, key: Res<Input<KeyCode>>| {
+            if key.just_pressed(KeyCode::A) {
+                for (_, mut value) in values.iter_mut() {
+                    value.0 *= 10;
+                }
+            }
+        });
+        let mut state = SystemState::<UndoScheduler<UndoEvent>>::new(&mut app.world);
+        state.get_mut(&mut app.world).reserve_default();
+        state.apply(&mut app.world);
+
+        app.world.resource_mut::<Input<KeyCode>>().press(KeyCode::A);
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 1);
+        assert_eq!(app.world.resource::<UndoReservedArea<UndoEvent>>().0.len(), 1);
+        app.world.resource_mut::<Input<KeyCode>>().reset(KeyCode::A);
+        app.update();
+        assert_eq!(**app.world.resource::<UndoCounter>(), 1);
+
+        goto(&mut app, 0);
+        assert_eq!(app.world.get::<Value>(first), Some(&Value(1)));
+        assert_eq!(app.world.get::<Value>(second), Some(&Value(2)));
+    }
+
+
+    #[test]
+    fn undo_query_ignores_entities_not_written_to() {
+        let mut app = new_app();
+        app.world.spawn(Value(1));
+        app.world.spawn(Value(2));
+        app.add_systems(Update, |mut values: UndoQuery<Value>| {
+            for (_, value) in values.iter_mut() {
+                assert!(0 < value.0);
+            }
+        });
+        app.update();
+        app.update();
+
+        assert_eq!(**app.world.resource::<UndoCounter>(), 0);
+    }
+
+
+    #[test]
+    fn property_edit_is_undone_by_path() {
+        #[derive(Component, Reflect, Default, PartialEq, Debug)]
+        #[reflect(Component)]
+        struct Position {
+            x: f32,
+            y: f32,
+        }
+
+        let mut app = new_app();
+        app.register_type::<Position>();
+        let entity = app.world.spawn(Position { x: 1., y: 2. }).id();
+        app.add_systems(Startup, move |mut commands: Commands| {
+            commands
+                .entity(entity)
+                .set_property_undoable(TypeId::of::<Position>(), "x", Box::new(5_f32));
+        });
+        app.update();
+        assert_eq!(app.world.get::<Position>(entity), Some(&Position { x: 5., y: 2. }));
+
+        goto(&mut app, 0);
+        assert_eq!(app.world.get::<Position>(entity), Some(&Position { x: 1., y: 2. }));
+
+        goto(&mut app, 1);
+        assert_eq!(app.world.get::<Position>(entity), Some(&Position { x: 5., y: 2. }));
+
+        let mut state = SystemState::<Commands>::new(&mut app.world);
+        state
+            .get_mut(&mut app.world)
+            .entity(entity)
+            .set_property_undoable(TypeId::of::<Position>(), "y", Box::new(5_u32));
+        state.apply(&mut app.world);
+        assert_eq!(app.world.get::<Position>(entity), Some(&Position { x: 5., y: 2. }));
+        assert_eq!(**app.world.resource::<UndoCounter>(), 1);
+    }
+
+
+    #[test]
+    fn snapshot_restores_captured_entities() {
+        fn markers(app: &mut App) -> Vec<usize> {
+            let mut markers = app
+                .world
+                .query::<&Marker>()
+                .iter(&app.world)
+                .map(|marker| marker.0)
+                .collect::<Vec<_>>();
+            markers.sort();
+            markers
+        }
+
+        let mut app = new_app();
+        app.register_type::<Marker>();
+        let first = app.world.spawn(Marker(1)).id();
+        app.world.spawn(Marker(2));
+        app.add_systems(Startup, move |mut scheduler: UndoSnapshotScheduler, mut commands: Commands| {
+            scheduler.snapshot::<With<Marker>>();
+            commands.entity(first).insert(Marker(10));
+            commands.spawn(Marker(3));
+        });
+        app.update();
+        assert_eq!(markers(&mut app), [2, 3, 10]);
+
+        goto(&mut app, 0);
+        assert_eq!(markers(&mut app), [1, 2]);
+
+        goto(&mut app, 1);
+        assert_eq!(markers(&mut app), [2, 3, 10]);
+    }
+
+
+    #[test]
+    fn snapshot_restores_parent_outside_filter() {
+        let mut app = new_app();
+        app.register_type::<Marker>();
+        let parent = app.world.spawn_empty().id();
+        let sibling = app.world.spawn_empty().set_parent(parent).id();
+        let child = app.world.spawn(Marker(1)).set_parent(parent).id();
+        app.add_systems(Startup, move |mut scheduler: UndoSnapshotScheduler, mut commands: Commands| {
+            scheduler.snapshot::<With<Marker>>();
+            commands.entity(child).insert(Marker(2));
+        });
+        app.update();
+
+        goto(&mut app, 0);
+        let (restored, restored_parent) = app
+            .world
+            .query_filtered::<(Entity, &Parent), With<Marker>>()
+            .single(&app.world);
+        assert_ne!(restored, child);
+        assert_eq!(restored_parent.get(), parent);
+        assert_eq!(app.world.get::<Children>(parent).unwrap().to_vec(), [sibling, restored]);
+
+        goto(&mut app, 1);
+        let redone = app.world.query_filtered::<Entity, With<Marker>>().single(&app.world);
+        assert_eq!(app.world.get::<Marker>(redone), Some(&Marker(2)));
+        assert_eq!(app.world.get::<Children>(parent).unwrap().to_vec(), [sibling, redone]);
+    }
+
+
+    #[test]
+    fn snapshot_shares_unchanged_entities_within_budget() {
+        let mut app = new_app();
+        app.register_type::<Marker>();
+        app.world.spawn(Marker(1));
+        app.add_systems(Update, |mut scheduler: UndoSnapshotScheduler, mut commands: Commands, mut frame: Local<usize>| {
+            scheduler.snapshot::<With<Marker>>();
+            if *frame < 3 {
+                commands.add(|world: &mut World| {
+                    for mut marker in world.query::<&mut Marker>().iter_mut(world) {
+                        marker.0 += 1;
+                    }
+                });
+            }
+            *frame += 1;
+        });
+        for _ in 0..4 {
+            app.update();
+        }
+        let footprint = app.world.resource::<UndoSnapshotStore>().footprint();
+        assert_eq!(footprint % 4, 0);
+
+        app.update();
+        assert_eq!(app.world.resource::<UndoSnapshotStore>().footprint(), footprint);
+
+        let snapshot = footprint / 4;
+        app.insert_resource(UndoSnapshotMemoryBudget(snapshot * 3));
+        app.update();
+        assert_eq!(app.world.resource::<UndoSnapshotStore>().footprint(), snapshot * 3);
+        assert_eq!(app.world.resource::<UndoRegisteredArea<UndoSnapshotEvent>>().len(), 5);
+    }
+
+
+    #[test]
+    fn snapshot_footprint_counts_heap_memory() {
+        #[derive(Component, Reflect, Default, PartialEq, Debug)]
+        #[reflect(Component)]
+        struct Label(String);
+
+        let mut app = new_app();
+        app.register_type::<Label>();
+        app.world.spawn(Label("label".repeat(1000)));
+        app.add_systems(Startup, |mut scheduler: UndoSnapshotScheduler| {
+            scheduler.snapshot::<With<Label>>();
+        });
+        app.update();
+        assert!(5000 < app.world.resource::<UndoSnapshotStore>().footprint());
+    }
+
+
+    #[test]
+    fn snapshot_shares_components_of_restored_entities() {
+        let mut app = new_app();
+        app.register_type::<Marker>();
+        app.world.spawn(Marker(1));
+        app.world.spawn(Marker(2));
+        app.add_systems(Startup, |mut scheduler: UndoSnapshotScheduler| {
+            scheduler.snapshot::<With<Marker>>();
+        });
+        app.update();
+        let footprint = app.world.resource::<UndoSnapshotStore>().footprint();
+
+        goto(&mut app, 0);
+        assert_eq!(app.world.resource::<UndoEntityMap>().len(), 2);
+        assert_eq!(app.world.resource::<UndoSnapshotStore>().footprint(), footprint);
+
+        let mut state = SystemState::<UndoSnapshotScheduler>::new(&mut app.world);
+        state.get_mut(&mut app.world).snapshot::<With<Marker>>();
+        state.apply(&mut app.world);
+        assert_eq!(app.world.resource::<UndoSnapshotStore>().footprint(), footprint);
+    }
+
+
+    fn target_app() -> App {
+        let mut app = new_app();
+        app
+            .add_undo_event::<Target>()
+            .init_resource::<Targeted>()
+            .add_systems(Update, |mut er: EventReader<Target>, mut invalid: EventReader<UndoInvalidEntryEvent>, mut targeted: ResMut<Targeted>| {
+                targeted.0.extend(er.iter().map(|target| target.0));
+                targeted.1.extend(invalid.iter().cloned());
+            });
+        app
+    }
+
+
+    /// Registers a [`Target`] of a new entity as step 1, then despawns the entity undoably as step 2.
+    fn register_and_despawn_target(app: &mut App) -> Entity {
+        let entity = app.world.spawn_empty().id();
+        app.add_systems(Startup, move |mut scheduler: UndoScheduler<Target>| {
+            scheduler.register(Target(entity));
+        });
+        app.add_systems(PostUpdate, move |mut commands: Commands, mut once: Local<bool>| {
+            if !std::mem::replace(&mut *once, true) {
+                commands.entity(entity).despawn_undoable();
+            }
+        });
+        app.update();
+        entity
+    }
+
+
+    fn goto(app: &mut App, step: usize) {
+        let mut state = SystemState::<UndoRequester>::new(&mut app.world);
+        state.get_mut(&mut app.world).goto(step);
+        state.apply(&mut app.world);
+        app.update();
+        app.update();
+    }
+
+
+    fn undo(mut req: UndoRequester, key: Res<Input<KeyCode>>) {
+        if key.just_pressed(KeyCode::R) {
+            req.undo();
+        }
+        if key.just_pressed(KeyCode::T) {
+            req.redo();
         }
     }
 
@@ -0,0 +1,100 @@
+use std::any::TypeId;
+
+use bevy::prelude::Resource;
+
+use crate::request::UndoDirection;
+
+/// Present while a step holding events that require completion is being undone or redone.
+///
+/// Such events are set up via [`AppUndoEx::defer_undo_completion`](crate::extension::AppUndoEx::defer_undo_completion).
+/// The systems that apply them, for example over several frames of an animation, call [`complete`](UndoInProgress::complete)
+/// once per event when they are done; until then, further undo and redo requests wait according to the [`UndoQueuePolicy`].
+#[derive(Resource, Debug, Clone, Eq, PartialEq, Hash)]
+pub struct UndoInProgress {
+    no: usize,
+    direction: UndoDirection,
+    pending: usize,
+}
+
+
+impl UndoInProgress {
+    #[inline(always)]
+    pub(crate) const fn new(no: usize, direction: UndoDirection, pending: usize) -> Self {
+        Self {
+            no,
+            direction,
+            pending,
+        }
+    }
+
+
+    /// Returns the step being undone or redone.
+    #[inline(always)]
+    pub const fn no(&self) -> usize {
+        self.no
+    }
+
+
+    #[inline(always)]
+    pub const fn direction(&self) -> UndoDirection {
+        self.direction
+    }
+
+
+    /// Acknowledges that one of the events of the step has been applied.
+    #[inline]
+    pub fn complete(&mut self) {
+        self.pending = self.pending.saturating_sub(1);
+    }
+
+
+    /// Adds events to acknowledge, sent by a step chained to this one.
+    #[inline]
+    pub(crate) fn add(&mut self, pending: usize) {
+        self.pending += pending;
+    }
+
+
+    /// Returns true if all events of the step have been acknowledged.
+    #[inline(always)]
+    pub const fn is_complete(&self) -> bool {
+        self.pending == 0
+    }
+}
+
+
+/// What happens to undo and redo requests made while an [`UndoInProgress`] is not complete.
+#[derive(Resource, Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum UndoQueuePolicy {
+    /// All requests wait and are applied in order.
+    #[default]
+    Queue,
+
+    /// Requests are rejected.
+    Discard,
+
+    /// At most the given number of requests wait; the others are rejected.
+    Bounded(usize),
+}
+
+
+/// The undo event types set up via [`AppUndoEx::defer_undo_completion`](crate::extension::AppUndoEx::defer_undo_completion),
+/// whose sent events have to be acknowledged.
+#[derive(Resource, Debug, Default)]
+pub(crate) struct UndoCompletions(Vec<TypeId>);
+
+
+impl UndoCompletions {
+    #[inline]
+    pub fn push(&mut self, type_id: TypeId) {
+        if !self.0.contains(&type_id) {
+            self.0.push(type_id);
+        }
+    }
+
+
+    #[inline]
+    pub fn is_deferred(&self, type_id: TypeId) -> bool {
+        self.0.contains(&type_id)
+    }
+}
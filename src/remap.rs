@@ -0,0 +1,127 @@
+use std::any::TypeId;
+use std::collections::HashMap;
+
+use bevy::ecs::entity::EntityMap;
+use bevy::prelude::{DetectChanges, Entity, Event, Res, ResMut, Resource, World};
+
+use crate::reserve::UndoReservedArea;
+use crate::UndoRegisteredArea;
+
+/// Maps the ids of entities that were despawned and then spawned again by undo or redo to their new ids.
+///
+/// Restoring an entity gives it a new id, so the entries that still refer to the old one
+/// are rewritten through [`UndoMapEntities`].
+#[derive(Resource, Debug, Default, Clone)]
+pub struct UndoEntityMap(HashMap<Entity, Entity>);
+
+
+impl UndoEntityMap {
+    /// Returns the current id of the entity, following all the restorations it went through.
+    ///
+    /// The entity itself is returned if it has never been restored.
+    pub fn get(&self, mut entity: Entity) -> Entity {
+        while let Some(next) = self.0.get(&entity) {
+            entity = *next;
+        }
+        entity
+    }
+
+
+    /// Replaces the entity with its current id.
+    #[inline]
+    pub fn map(&self, entity: &mut Entity) {
+        *entity = self.get(*entity);
+    }
+
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+
+    #[inline]
+    pub(crate) fn insert(&mut self, from: Entity, to: Entity) {
+        if from != to {
+            self.0.insert(from, to);
+        }
+    }
+
+
+    /// Records all the entities of the map, which has been filled by spawning a scene.
+    pub(crate) fn record(world: &mut World, entity_map: &EntityMap) {
+        if let Some(mut map) = world.get_resource_mut::<Self>() {
+            for (from, to) in entity_map.iter() {
+                map.insert(from, to);
+            }
+        }
+    }
+}
+
+
+/// The undo events whose handling may restore entities with new ids, and the undo events rewritten through [`UndoMapEntities`].
+///
+/// While any event is rewritten, the request queue waits for the next frame after sending events that may restore entities,
+/// so that the following steps are rewritten through the [`UndoEntityMap`] before they are sent.
+#[derive(Resource, Debug, Default)]
+pub(crate) struct UndoRemapping {
+    restorers: Vec<TypeId>,
+    mapped: Vec<TypeId>,
+}
+
+
+impl UndoRemapping {
+    #[inline]
+    pub fn push_restorer(&mut self, type_id: TypeId) {
+        if !self.restorers.contains(&type_id) {
+            self.restorers.push(type_id);
+        }
+    }
+
+
+    #[inline]
+    pub fn push_mapped(&mut self, type_id: TypeId) {
+        if !self.mapped.contains(&type_id) {
+            self.mapped.push(type_id);
+        }
+    }
+
+
+    /// Returns true if the queue has to wait for the remap after sending events of the type.
+    #[inline]
+    pub fn waits_after(&self, type_id: TypeId) -> bool {
+        !self.mapped.is_empty() && self.restorers.contains(&type_id)
+    }
+}
+
+
+/// Rewrites the entities held by an undo entry after they have been restored with new ids.
+///
+/// Implement it for an event and call [`AppUndoEx::map_undo_entities`](crate::extension::AppUndoEx::map_undo_entities),
+/// then the events kept in the history are rewritten whenever an entity is restored.
+pub trait UndoMapEntities {
+    fn map_entities(&mut self, map: &UndoEntityMap);
+}
+
+
+pub(crate) fn map_undo_entities_system<E: Event + UndoMapEntities>(
+    map: Res<UndoEntityMap>,
+    mut registered: ResMut<UndoRegisteredArea<E>>,
+    mut reserved: ResMut<UndoReservedArea<E>>,
+) {
+    if !map.is_changed() {
+        return;
+    }
+    for event in registered.events_mut() {
+        event.map_entities(&map);
+    }
+    for event in reserved.events_mut() {
+        event.map_entities(&map);
+    }
+}
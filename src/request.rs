@@ -1,23 +1,394 @@
 use bevy::ecs::system::SystemParam;
-use bevy::prelude::{Event, EventWriter, Res};
+use std::collections::VecDeque;
+
+use bevy::log::warn;
+use bevy::prelude::{Mut, Res, ResMut, Resource, World};
+use crate::context::{UndoApplying, UndoOrigin};
 use crate::counter::UndoCounter;
+use crate::extension::{UndoAreaOps, UndoAreas};
+use crate::progress::{UndoCompletions, UndoInProgress, UndoQueuePolicy};
+use crate::remap::UndoRemapping;
+use crate::tree::UndoTree;
+use crate::undo_event::UndoHandle;
+use crate::veto::{UndoVerdict, UndoVetoedEvent, UndoVetoes};
+
+/// Whether a step is undone or redone.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum UndoDirection {
+    Undo,
+    Redo,
+}
+
+
+/// A step to undo or redo, resolved from an [`UndoRequest`] when it is processed.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub(crate) struct UndoStep {
+    pub no: usize,
+    pub direction: UndoDirection,
+}
+
+
+/// A request made via [`UndoRequester`], which waits in the [`UndoRequestQueue`].
+///
+/// Requests are relative, so the step they refer to is resolved only when they are processed.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub(crate) enum UndoRequest {
+    Undo,
+    Redo,
+    Goto(usize),
+
+    /// Undoes only the entries registered with the handle, without moving the counter.
+    Entry(UndoHandle),
+
+    /// Switches to the branch with the id, which is checked by veto hooks as a redo of the step after its fork.
+    SwitchBranch(usize),
+}
 
-#[derive(Event, Default, PartialEq, Debug, Copy, Clone, )]
-pub(crate) struct RequestUndoEvent(pub UndoCounter);
+
+impl UndoRequest {
+    /// Returns the next step to apply for this request, or `None` if it is done.
+    pub fn next_step(&self, counter: usize, tree: &UndoTree) -> Option<UndoStep> {
+        let undo = UndoStep { no: counter, direction: UndoDirection::Undo };
+        let redo = UndoStep { no: counter + 1, direction: UndoDirection::Redo };
+        match *self {
+            Self::Undo => (0 < counter).then_some(undo),
+            Self::Redo => (counter < tree.head()).then_some(redo),
+            Self::Goto(step) if step < counter => Some(undo),
+            Self::Goto(step) => (counter < step.min(tree.head())).then_some(redo),
+            Self::Entry(UndoHandle { no, .. }) => (0 < no && no <= counter).then_some(UndoStep { no, direction: UndoDirection::Undo }),
+            Self::SwitchBranch(id) => tree
+                .switchable_fork(counter, id)
+                .map(|fork| UndoStep { no: fork + 1, direction: UndoDirection::Redo }),
+        }
+    }
+}
+
+
+/// The requests of [`UndoRequester`] in the order they were made.
+#[derive(Resource, Debug, Default)]
+pub(crate) struct UndoRequestQueue(pub VecDeque<UndoRequest>);
 
 
 #[derive(SystemParam)]
 pub struct UndoRequester<'w> {
-    ew: EventWriter<'w, RequestUndoEvent>,
-    counter: Res<'w, UndoCounter>
+    queue: ResMut<'w, UndoRequestQueue>,
+    counter: Res<'w, UndoCounter>,
 }
 
 
 impl<'w> UndoRequester<'w> {
     /// request undo-operation.
     /// This will send　the most recent event registered via [`UndoScheduler`](crate::undo_event::UndoScheduler).
+    ///
+    /// The request can be rejected or postponed by the hooks added via [`AppUndoEx::add_undo_veto`](crate::extension::AppUndoEx::add_undo_veto).
     #[inline(always)]
     pub fn undo(&mut self) {
-        self.ew.send(RequestUndoEvent(*self.counter));
+        self.queue.0.push_back(UndoRequest::Undo);
     }
-}
\ No newline at end of file
+
+
+    /// request redo-operation.
+    /// This will send the redo-events of the most recently undone step.
+    ///
+    /// Only entries registered with a redo-event, such as via [`UndoScheduler::register_with_redo`](crate::undo_event::UndoScheduler::register_with_redo),
+    /// can be redone; otherwise the request is rejected and reported by [`UndoVetoedEvent`](crate::veto::UndoVetoedEvent).
+    #[inline(always)]
+    pub fn redo(&mut self) {
+        self.queue.0.push_back(UndoRequest::Redo);
+    }
+
+
+    /// request to undo only the entry identified by `handle`, leaving the later entries in place.
+    ///
+    /// The undo counter is not changed, and the entry is removed from the history, so it can not be redone.
+    /// Nothing is sent if the entry has already been undone, even if another entry has been registered at its step since.
+    /// The request is queued and checked by veto hooks as an undo of the entry's step.
+    #[inline(always)]
+    pub fn undo_entry(&mut self, handle: UndoHandle) {
+        self.queue.0.push_back(UndoRequest::Entry(handle));
+    }
+
+
+    /// request to undo or redo as many steps as needed to reach `step`.
+    ///
+    /// Steps are undone from the latest one downwards, or redone from the oldest one upwards,
+    /// so the events are sent in the same order as calling [`undo`](UndoRequester::undo) or [`redo`](UndoRequester::redo) repeatedly.
+    /// `step` is clamped to [`UndoTree::head`].
+    /// The events are told apart by [`UndoOrigin::Replay`](crate::context::UndoOrigin::Replay) in the [`UndoContext`](crate::context::UndoContext).
+    #[inline(always)]
+    pub fn goto(&mut self, step: usize) {
+        self.queue.0.push_back(UndoRequest::Goto(step));
+    }
+
+
+    /// Returns the current step of the undo counter.
+    ///
+    /// This is the value [`goto`](UndoRequester::goto) compares with; 0 means all steps are undone.
+    #[inline(always)]
+    pub fn current_step(&self) -> usize {
+        **self.counter
+    }
+
+
+    /// request to switch the current redo path to the [`UndoBranch`](crate::tree::UndoBranch) with the given id.
+    ///
+    /// The branch can only be switched to after undoing back to its [`fork`](crate::tree::UndoBranch::fork);
+    /// then call [`redo`](UndoRequester::redo) to walk along it.
+    /// The request is queued and checked by veto hooks as a redo of the step after the fork.
+    #[inline(always)]
+    pub fn switch_branch(&mut self, id: usize) {
+        self.queue.0.push_back(UndoRequest::SwitchBranch(id));
+    }
+}
+
+
+/// Takes the requests from the [`UndoRequestQueue`] one step at a time, and applies the steps allowed by all veto hooks.
+///
+/// Each step is resolved against the current undo counter, which is updated as soon as the step is applied,
+/// so requests made in the same frame undo or redo consecutive steps.
+/// A postponed step holds the requests behind it until the next frame, so that steps are never skipped.
+/// A redo of a step without redo-events is rejected, so the counter never passes a step that sent nothing.
+/// An undo or redo request goes through the steps chained to each other by reservations at once,
+/// and only if all of them are allowed.
+/// After a step that may restore entities of remapped events, the remaining requests wait for the next frame,
+/// so that their entities are rewritten to the waits ones first.
+/// While an [`UndoInProgress`] is not complete, requests wait or are rejected according to the [`UndoQueuePolicy`].
+pub(crate) fn request_queue_system(world: &mut World) {
+    if world.get_resource::<UndoInProgress>().is_some_and(UndoInProgress::is_complete) {
+        world.remove_resource::<UndoInProgress>();
+    }
+    let policy = world.get_resource::<UndoQueuePolicy>().copied().unwrap_or_default();
+
+    world.resource_scope(|world, mut queue: Mut<UndoRequestQueue>| {
+        while let Some(request) = queue.0.front().copied() {
+            let counter = **world.resource::<UndoCounter>();
+            let Some(step) = resolve(world, request, counter) else {
+                match request {
+                    UndoRequest::SwitchBranch(id) => warn!("undo branch {id} can not be switched to from step {counter}"),
+                    UndoRequest::Entry(handle) => warn!("undo entry of step {} is no longer in the history", handle.no()),
+                    _ => {}
+                }
+                queue.0.pop_front();
+                continue;
+            };
+            if world.contains_resource::<UndoInProgress>() {
+                discard_overflow(world, &mut queue, policy, counter);
+                return;
+            }
+
+            let steps = chain(world, request, step);
+            let denied = steps
+                .iter()
+                .find_map(|step| match verdict(world, request, *step) {
+                    UndoVerdict::Allow => None,
+                    verdict => Some((*step, verdict))
+                });
+            let Some((step, verdict)) = denied else {
+                let mut waits = false;
+                for step in steps {
+                    waits |= allow(world, request, step);
+                }
+                if !matches!(request, UndoRequest::Goto(_)) {
+                    queue.0.pop_front();
+                }
+                if waits {
+                    return;
+                }
+                continue;
+            };
+
+            if matches!(verdict, UndoVerdict::Reject(_)) {
+                queue.0.pop_front();
+            }
+            world.send_event(UndoVetoedEvent {
+                no: step.no,
+                direction: step.direction,
+                verdict: verdict.clone(),
+            });
+            if matches!(verdict, UndoVerdict::Postpone(_)) {
+                return;
+            }
+        }
+    });
+}
+
+
+/// Returns the next step to apply for the request, or `None` if it is done.
+///
+/// The step of an entry is found by the id of its handle, so a stale handle resolves to nothing.
+fn resolve(world: &mut World, request: UndoRequest, counter: usize) -> Option<UndoStep> {
+    match request {
+        UndoRequest::Entry(handle) => world
+            .resource::<UndoAreas>()
+            .ops()
+            .into_iter()
+            .find_map(|ops| (ops.find_entry)(world, handle.id))
+            .map(|no| UndoStep { no, direction: UndoDirection::Undo }),
+        _ => request.next_step(counter, world.resource::<UndoTree>()),
+    }
+}
+
+
+/// Returns the step with the steps chained to it by reservations, in the order they are applied.
+fn chain(world: &mut World, request: UndoRequest, step: UndoStep) -> Vec<UndoStep> {
+    let mut steps = vec![step];
+    match request {
+        UndoRequest::Undo => {
+            let mut no = step.no;
+            while 1 < no && is_chained(world, no) {
+                no -= 1;
+                steps.push(UndoStep { no, direction: UndoDirection::Undo });
+            }
+        }
+        UndoRequest::Redo => {
+            let head = world.resource::<UndoTree>().head();
+            let mut no = step.no;
+            while no < head && is_chained(world, no + 1) {
+                no += 1;
+                steps.push(UndoStep { no, direction: UndoDirection::Redo });
+            }
+        }
+        _ => {}
+    }
+    steps
+}
+
+
+/// Returns the verdict of the veto hooks, or rejects the redo of a step without redo-events.
+fn verdict(world: &mut World, request: UndoRequest, step: UndoStep) -> UndoVerdict {
+    let redoes = step.direction == UndoDirection::Redo && !matches!(request, UndoRequest::SwitchBranch(_));
+    if redoes && !has_redo(world, step.no) {
+        UndoVerdict::Reject(NOTHING_TO_REDO.to_string())
+    } else {
+        world.resource::<UndoVetoes>().verdict(world, step)
+    }
+}
+
+
+/// Rejects the requests made while a step is in progress that do not fit in the queue allowed by the policy.
+fn discard_overflow(
+    world: &mut World,
+    queue: &mut UndoRequestQueue,
+    policy: UndoQueuePolicy,
+    counter: usize,
+) {
+    let max = match policy {
+        UndoQueuePolicy::Queue => return,
+        UndoQueuePolicy::Discard => 0,
+        UndoQueuePolicy::Bounded(max) => max,
+    };
+    let rejected = queue.0.split_off(max.min(queue.0.len()));
+    for request in rejected {
+        if let Some(step) = request.next_step(counter, world.resource::<UndoTree>()) {
+            world.send_event(UndoVetoedEvent {
+                no: step.no,
+                direction: step.direction,
+                verdict: UndoVerdict::Reject(IN_PROGRESS.to_string()),
+            });
+        }
+    }
+}
+
+
+const IN_PROGRESS: &str = "another step is in progress";
+
+
+const NOTHING_TO_REDO: &str = "the step has no redo-events";
+
+
+/// Returns true if any registered area undoes and redoes the step together with the previous one.
+fn is_chained(world: &mut World, no: usize) -> bool {
+    world
+        .resource::<UndoAreas>()
+        .ops()
+        .into_iter()
+        .any(|ops| (ops.is_chained)(world, no))
+}
+
+
+/// Returns true if any registered area can redo the step.
+fn has_redo(world: &mut World, no: usize) -> bool {
+    world
+        .resource::<UndoAreas>()
+        .ops()
+        .into_iter()
+        .any(|ops| (ops.has_redo)(world, no))
+}
+
+
+/// Applies the step to the registered areas and the undo counter.
+///
+/// Returns true if the queue has to wait for the entities restored by the sent events to be remapped.
+fn allow(world: &mut World, request: UndoRequest, UndoStep { no, direction }: UndoStep) -> bool {
+    match request {
+        UndoRequest::SwitchBranch(id) => {
+            switch_branch(world, id);
+            return false;
+        }
+        UndoRequest::Entry(_) => {
+            return apply_areas(world, no, direction, UndoOrigin::Undo, |ops| ops.undo_entry);
+        }
+        _ => {}
+    }
+
+    let origin = match (request, direction) {
+        (UndoRequest::Goto(_), _) => UndoOrigin::Replay,
+        (_, UndoDirection::Undo) => UndoOrigin::Undo,
+        (_, UndoDirection::Redo) => UndoOrigin::Redo,
+    };
+    let waits = apply_areas(world, no, direction, origin, |ops| match direction {
+        UndoDirection::Undo => ops.undo,
+        UndoDirection::Redo => ops.redo,
+    });
+    match direction {
+        UndoDirection::Undo => world.resource_mut::<UndoCounter>().decrement(),
+        UndoDirection::Redo => world.resource_scope(|world, mut tree: Mut<UndoTree>| {
+            tree.redo(&mut world.resource_mut::<UndoCounter>(), no);
+        }),
+    }
+    waits
+}
+
+
+/// Applies the operation chosen by `op` to every registered area,
+/// and marks the step as in progress if events that require completion were sent.
+///
+/// Returns true if the queue has to wait for the entities restored by the sent events to be remapped.
+fn apply_areas(
+    world: &mut World,
+    no: usize,
+    direction: UndoDirection,
+    origin: UndoOrigin,
+    op: impl Fn(&UndoAreaOps) -> fn(&mut World, usize, UndoOrigin) -> usize,
+) -> bool {
+    let mut pending = 0;
+    let mut waits = false;
+    for ops in world.resource::<UndoAreas>().ops() {
+        let sent = op(&ops)(world, no, origin);
+        if 0 < sent {
+            world.resource_mut::<UndoApplying>().start();
+            waits |= world.resource::<UndoRemapping>().waits_after(ops.type_id);
+        }
+        if world.resource::<UndoCompletions>().is_deferred(ops.type_id) {
+            pending += sent;
+        }
+    }
+    if 0 < pending {
+        match world.get_resource_mut::<UndoInProgress>() {
+            Some(mut progress) => progress.add(pending),
+            None => world.insert_resource(UndoInProgress::new(no, direction, pending)),
+        }
+    }
+    waits
+}
+
+
+/// Switches the current redo path, and sends the event that moves the entries of each registered area.
+fn switch_branch(world: &mut World, id: usize) {
+    let event = world.resource_scope(|world, mut tree: Mut<UndoTree>| {
+        tree.switch(world.resource::<UndoCounter>(), id)
+    });
+    if let Some(event) = event {
+        world.send_event(event);
+    }
+}
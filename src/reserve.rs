@@ -1,7 +1,8 @@
-use std::collections::VecDeque;
 use std::fmt::Debug;
 use std::ops::Deref;
 use bevy::prelude::{Event, Resource};
+use crate::history::UndoEntryMeta;
+use crate::undo_event::RedoEvent;
 
 
 #[derive(Event, Clone)]
@@ -12,10 +13,12 @@ pub(crate) struct RequestCommitReservationsFromSchedulerEvent;
 pub(crate) struct RequestCommitReservationsEvent;
 
 
-#[derive(Event, Clone)]
-pub(crate) struct UndoReserveEvent<E: Event + Clone> {
+pub(crate) struct UndoReserveEvent<E: Event> {
     pub inner: E,
+    pub redo: Option<RedoEvent<E>>,
     pub reserve_no: usize,
+    pub id: usize,
+    pub meta: UndoEntryMeta,
 }
 
 
@@ -49,29 +52,34 @@ impl ReserveCounter {
 
 
 #[derive(Resource)]
-pub(crate) struct UndoReservedArea<E: Event + Clone>(pub(crate) Vec<UndoReserveEvent<E>>);
+pub(crate) struct UndoReservedArea<E: Event>(pub(crate) Vec<UndoReserveEvent<E>>);
 
 
-impl<E: Event + Clone> UndoReservedArea<E> {
+impl<E: Event> UndoReservedArea<E> {
     #[inline]
     pub fn push(&mut self, event: UndoReserveEvent<E>) {
         self.0.push(event);
     }
 
 
+    /// Returns the undo-events and redo-events of all reserved entries.
+    pub fn events_mut(&mut self) -> impl Iterator<Item=&mut E> {
+        self.0
+            .iter_mut()
+            .flat_map(|event| std::iter::once(&mut event.inner).chain(event.redo.as_mut().map(|redo| &mut redo.inner)))
+    }
+
+
+    /// Takes all reserved events in the order they were reserved.
     #[inline]
-    pub fn pop_front(&mut self) -> Option<UndoReserveEvent<E>> {
-        self.0.pop()
-        // if self.0.is_empty(){
-        //     None
-        // }else{
-        //     Some(self.0.remove(0))
-        // }
+    pub fn drain(&mut self) -> impl Iterator<Item=UndoReserveEvent<E>> + '_ {
+        self.0.sort_by_key(|event| event.reserve_no);
+        self.0.drain(..)
     }
 }
 
 
-impl<E: Event + Clone> Default for UndoReservedArea<E> {
+impl<E: Event> Default for UndoReservedArea<E> {
     #[inline(always)]
     fn default() -> Self {
         Self(Vec::new())
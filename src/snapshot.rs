@@ -0,0 +1,450 @@
+use std::any::TypeId;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use bevy::app::{App, Plugin, Update};
+use bevy::ecs::entity::EntityMap;
+use bevy::ecs::event::{Events, ManualEventReader};
+use bevy::ecs::query::ReadOnlyWorldQuery;
+use bevy::ecs::system::{Command, SystemParam, SystemState};
+use bevy::hierarchy::{BuildWorldChildren, Children, Parent};
+use bevy::log::warn;
+use bevy::prelude::{Commands, Entity, Event, IntoSystemConfigs, Local, Mut, Resource, World};
+use bevy::reflect::{Reflect, ReflectRef};
+use bevy::scene::{DynamicEntity, DynamicScene, DynamicSceneBuilder};
+
+use crate::extension::AppUndoEx;
+use crate::remap::{UndoEntityMap, UndoRemapping};
+use crate::request::request_queue_system;
+use crate::undo_event::UndoScheduler;
+use crate::UndoRegisteredArea;
+
+/// Records the reflected state of the world instead of undo-events.
+///
+/// Each call of [`snapshot`](UndoSnapshotScheduler::snapshot) captures the reflected components of the entities
+/// matching a filter and registers them as a new step. Undo replaces the entities that match the filter
+/// at that time with the captured ones, spawned from a [`DynamicScene`], and redo restores the state they had before the undo.
+///
+/// Only components registered with `#[reflect(Component)]` are captured.
+/// Each snapshot is stored as a delta of the previous one: an entity whose components are all unchanged is shared with it,
+/// and so is each unchanged component value of the other entities; see [`UndoSnapshotMemoryBudget`] to limit the total.
+/// Restored entities get new ids; references between the restored entities
+/// are remapped if the component is registered with `#[reflect(MapEntities)]`.
+/// The hierarchy is captured separately, so restored entities are attached again to their parents and children,
+/// including those that do not match the filter.
+#[derive(SystemParam)]
+pub struct UndoSnapshotScheduler<'w, 's> {
+    commands: Commands<'w, 's>,
+}
+
+
+impl<'w, 's> UndoSnapshotScheduler<'w, 's> {
+    /// Captures the entities matching `F` and registers the snapshot as a new step.
+    ///
+    /// The snapshot is taken when the commands of the system are applied,
+    /// so changes made afterwards through [`Commands`] are undone by it.
+    #[inline]
+    pub fn snapshot<F: ReadOnlyWorldQuery + 'static>(&mut self) {
+        self.commands.add(TakeSnapshot(matching::<F>));
+    }
+}
+
+
+/// Limits the memory used by the snapshots of [`UndoSnapshotScheduler`], in bytes.
+///
+/// The memory is estimated through reflection, from the size of each captured value and of the lists, maps and strings it holds.
+/// Entities and values shared between snapshots are counted once. While this resource exists and the limit is exceeded,
+/// the oldest snapshots are dropped from the history, so undoing their steps no longer changes the world.
+/// The latest snapshot is always kept.
+#[derive(Resource, Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[repr(transparent)]
+pub struct UndoSnapshotMemoryBudget(pub usize);
+
+
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default)]
+pub(crate) struct UndoSnapshotPlugin;
+
+
+impl Plugin for UndoSnapshotPlugin {
+    #[inline]
+    fn build(&self, app: &mut App) {
+        app
+            .add_undo_event::<UndoSnapshotEvent>()
+            .init_resource::<UndoSnapshotStore>()
+            .add_systems(Update, undo_snapshot_event_system.after(request_queue_system));
+        app.world.resource_mut::<UndoRemapping>().push_restorer(TypeId::of::<UndoSnapshotEvent>());
+    }
+}
+
+
+/// Restores the snapshot with the id, which is kept in [`UndoSnapshotStore`].
+///
+/// The same event is sent on undo and redo, since restoring a snapshot captures the state it replaces.
+#[derive(Event, Copy, Clone, Eq, PartialEq, Debug)]
+pub(crate) struct UndoSnapshotEvent(usize);
+
+
+#[derive(Resource, Default)]
+pub(crate) struct UndoSnapshotStore {
+    next_id: usize,
+    snapshots: HashMap<usize, WorldSnapshot>,
+
+    /// The number of times each entity and component value, identified by its address, is held by the snapshots,
+    /// with its estimated size.
+    values: HashMap<usize, (usize, usize)>,
+
+    /// The estimated size of all the values, counting shared ones once.
+    bytes: usize,
+}
+
+
+impl UndoSnapshotStore {
+    fn insert(&mut self, snapshot: WorldSnapshot) -> usize {
+        self.count(&snapshot.state);
+        self.next_id += 1;
+        self.snapshots.insert(self.next_id, snapshot);
+        self.next_id
+    }
+
+
+    fn remove(&mut self, id: usize) {
+        if let Some(snapshot) = self.snapshots.remove(&id) {
+            self.uncount(&snapshot.state);
+        }
+    }
+
+
+    /// Restores the snapshot with the id, and counts the values of the state it captures instead.
+    fn restore(&mut self, id: usize, world: &mut World) {
+        let Some(mut snapshot) = self.snapshots.remove(&id) else { return; };
+        self.uncount(&snapshot.state);
+        snapshot.restore(world);
+        self.count(&snapshot.state);
+        self.snapshots.insert(id, snapshot);
+    }
+
+
+    /// Returns the estimated number of bytes used by all snapshots, counting shared entities and values once.
+    #[inline(always)]
+    pub fn footprint(&self) -> usize {
+        self.bytes
+    }
+
+
+    fn count(&mut self, state: &SnapshotState) {
+        for (address, size) in state.allocations() {
+            let (count, _) = self.values.entry(address).or_insert((0, size));
+            if *count == 0 {
+                self.bytes += size;
+            }
+            *count += 1;
+        }
+    }
+
+
+    fn uncount(&mut self, state: &SnapshotState) {
+        for (address, _) in state.allocations() {
+            let Some((count, size)) = self.values.get_mut(&address) else { continue; };
+            *count -= 1;
+            if *count == 0 {
+                self.bytes -= *size;
+                self.values.remove(&address);
+            }
+        }
+    }
+
+
+    #[inline]
+    fn latest(&self) -> Option<&SnapshotState> {
+        self
+            .snapshots
+            .iter()
+            .max_by_key(|(id, _)| **id)
+            .map(|(_, snapshot)| &snapshot.state)
+    }
+
+
+    /// Drops the oldest snapshots, and their entries in the history, until the footprint fits in the budget.
+    fn fit(&mut self, UndoSnapshotMemoryBudget(budget): UndoSnapshotMemoryBudget, registered: &mut UndoRegisteredArea<UndoSnapshotEvent>) {
+        while 1 < self.snapshots.len() && budget < self.footprint() {
+            let Some(oldest) = self.snapshots.keys().min().copied() else { return; };
+            self.remove(oldest);
+            registered.retain(|UndoSnapshotEvent(id)| *id != oldest);
+        }
+    }
+
+
+    /// Drops the snapshots whose steps have been discarded from the history.
+    fn prune(&mut self, registered: &UndoRegisteredArea<UndoSnapshotEvent>) {
+        let alive = registered
+            .events()
+            .map(|UndoSnapshotEvent(id)| *id)
+            .collect::<HashSet<_>>();
+        if alive.len() < self.snapshots.len() {
+            let dropped = self
+                .snapshots
+                .keys()
+                .filter(|id| !alive.contains(id))
+                .copied()
+                .collect::<Vec<_>>();
+            for id in dropped {
+                self.remove(id);
+            }
+        }
+    }
+}
+
+
+pub(crate) struct WorldSnapshot {
+    state: SnapshotState,
+    filter: fn(&mut World) -> Vec<Entity>,
+}
+
+
+impl WorldSnapshot {
+    /// Replaces the entities matching the filter with the captured ones,
+    /// and keeps the replaced entities to restore them next time.
+    fn restore(&mut self, world: &mut World) {
+        let entities = (self.filter)(world);
+        let current = SnapshotState::capture(world, entities.iter().copied(), Some(&self.state));
+        for entity in entities {
+            detach(world, entity);
+            world.despawn(entity);
+        }
+
+        let mut entity_map = EntityMap::default();
+        if let Err(e) = self.state.to_scene().write_to_world(world, &mut entity_map) {
+            warn!("failed to restore the snapshot: {e}");
+        }
+        self.state.attach(world, &entity_map);
+        UndoEntityMap::record(world, &entity_map);
+        self.state = current;
+    }
+}
+
+
+/// Removes the entity from its parent and its children, so that no entity refers to it once it is despawned.
+fn detach(world: &mut World, entity: Entity) {
+    let mut entity_mut = world.entity_mut(entity);
+    if let Some(children) = entity_mut.get::<Children>().map(|children| children.to_vec()) {
+        entity_mut.remove_children(&children);
+    }
+    entity_mut.remove_parent();
+}
+
+
+/// The reflected components of a captured entity.
+type EntityComponents = Arc<[Arc<dyn Reflect>]>;
+
+
+/// The reflected components of the captured entities, and their place in the hierarchy.
+///
+/// Entities and components are shared via [`Arc`] with the snapshot the state was captured against, as long as they are equal,
+/// so the state only owns what changed since then.
+struct SnapshotState {
+    entities: Vec<(Entity, EntityComponents)>,
+
+    /// The parent of the captured entities and of their children, with the index among the children of the parent.
+    hierarchy: Vec<(Entity, Entity, usize)>,
+}
+
+
+impl SnapshotState {
+    /// Captures the entities, sharing the entities and components equal to those in `base`.
+    ///
+    /// The entities of `base` are looked up by their current ids, since they may have been restored since it was captured.
+    fn capture(world: &World, entities: impl Iterator<Item=Entity>, base: Option<&Self>) -> Self {
+        let entity_map = world.get_resource::<UndoEntityMap>();
+        let base: HashMap<Entity, &EntityComponents> = base
+            .map(|base| base
+                .entities
+                .iter()
+                .map(|(entity, components)| (entity_map.map_or(*entity, |map| map.get(*entity)), components))
+                .collect())
+            .unwrap_or_default();
+        let entities = entities.collect::<Vec<_>>();
+
+        let mut hierarchy = Vec::new();
+        for entity in entities.iter().copied() {
+            if let Some(parent) = world.get::<Parent>(entity).map(Parent::get) {
+                if !entities.contains(&parent) {
+                    hierarchy.push((entity, parent, child_index(world, parent, entity)));
+                }
+            }
+            for (index, child) in world.get::<Children>(entity).into_iter().flatten().enumerate() {
+                hierarchy.push((*child, entity, index));
+            }
+        }
+
+        let mut builder = DynamicSceneBuilder::from_world(world);
+        builder
+            .deny::<Parent>()
+            .deny::<Children>()
+            .extract_entities(entities.into_iter());
+        let entities = builder
+            .build()
+            .entities
+            .into_iter()
+            .map(|DynamicEntity { entity, components }| {
+                let base = base.get(&entity).copied();
+                let components = components
+                    .into_iter()
+                    .map(|component| share(component, base.map_or(&[], |base| base)))
+                    .collect::<Vec<_>>();
+                let unchanged = base.filter(|base| {
+                    base.len() == components.len() && base.iter().zip(&components).all(|(a, b)| Arc::ptr_eq(a, b))
+                });
+                (entity, unchanged.cloned().unwrap_or_else(|| Arc::from(components)))
+            })
+            .collect();
+        Self { entities, hierarchy }
+    }
+
+
+    /// Attaches the restored entities to their parents and children, mapped through `entity_map` if they were restored as well.
+    ///
+    /// Entities outside the state are attached if they still exist, and for children, if they have no other parent.
+    fn attach(&self, world: &mut World, entity_map: &EntityMap) {
+        let mut hierarchy = self.hierarchy.clone();
+        hierarchy.sort_by_key(|(_, _, index)| *index);
+        for (child, parent, index) in hierarchy {
+            let restored_child = entity_map.get(child);
+            let child = restored_child.unwrap_or(child);
+            let parent = entity_map.get(parent).unwrap_or(parent);
+            let orphan = world.get_entity(child).is_some_and(|child| !child.contains::<Parent>());
+            if (restored_child.is_none() && !orphan) || world.get_entity(parent).is_none() {
+                continue;
+            }
+            let len = world.get::<Children>(parent).map_or(0, |children| children.len());
+            world.entity_mut(parent).insert_children(index.min(len), &[child]);
+        }
+    }
+
+
+    fn to_scene(&self) -> DynamicScene {
+        DynamicScene {
+            resources: Vec::new(),
+            entities: self
+                .entities
+                .iter()
+                .map(|(entity, components)| DynamicEntity {
+                    entity: *entity,
+                    components: components.iter().map(|component| component.clone_value()).collect(),
+                })
+                .collect(),
+        }
+    }
+
+
+    /// Returns the address and the estimated size of each entity and component value held by the state.
+    fn allocations(&self) -> impl Iterator<Item=(usize, usize)> + '_ {
+        self.entities.iter().flat_map(|(_, components)| {
+            std::iter::once((address(components), std::mem::size_of_val(&**components)))
+                .chain(components.iter().map(|component| (address(component), estimate(component.as_ref()))))
+        })
+    }
+}
+
+
+/// Returns the index of the child among the children of the parent.
+#[inline]
+fn child_index(world: &World, parent: Entity, child: Entity) -> usize {
+    world
+        .get::<Children>(parent)
+        .and_then(|children| children.iter().position(|entity| *entity == child))
+        .unwrap_or_default()
+}
+
+
+/// Identifies an entity or a component value shared between snapshots.
+#[inline(always)]
+fn address<T: ?Sized>(value: &Arc<T>) -> usize {
+    Arc::as_ptr(value) as *const () as usize
+}
+
+
+/// Estimates the number of bytes used by the value, including the lists, maps and strings it holds.
+fn estimate(value: &dyn Reflect) -> usize {
+    std::mem::size_of_val(value) + estimate_heap(value)
+}
+
+
+/// Estimates the number of bytes the value holds on the heap.
+fn estimate_heap(value: &dyn Reflect) -> usize {
+    match value.reflect_ref() {
+        ReflectRef::Struct(value) => value.iter_fields().map(estimate_heap).sum(),
+        ReflectRef::TupleStruct(value) => value.iter_fields().map(estimate_heap).sum(),
+        ReflectRef::Tuple(value) => value.iter_fields().map(estimate_heap).sum(),
+        ReflectRef::List(value) => value.iter().map(estimate).sum(),
+        ReflectRef::Array(value) => value.iter().map(estimate_heap).sum(),
+        ReflectRef::Map(value) => value.iter().map(|(key, value)| estimate(key) + estimate(value)).sum(),
+        ReflectRef::Enum(value) => value.iter_fields().map(|field| estimate_heap(field.value())).sum(),
+        ReflectRef::Value(value) => value.downcast_ref::<String>().map_or(0, String::capacity),
+    }
+}
+
+
+/// Returns the component of the base which is equal to `component`, or a new [`Arc`] if there is none.
+fn share(component: Box<dyn Reflect>, base: &[Arc<dyn Reflect>]) -> Arc<dyn Reflect> {
+    base
+        .iter()
+        .find(|shared| {
+            shared.type_name() == component.type_name()
+                && shared.reflect_partial_eq(component.as_ref()).unwrap_or(false)
+        })
+        .cloned()
+        .unwrap_or_else(|| Arc::from(component))
+}
+
+
+struct TakeSnapshot(fn(&mut World) -> Vec<Entity>);
+
+
+impl Command for TakeSnapshot {
+    fn apply(self, world: &mut World) {
+        let entities = (self.0)(world);
+        let state = SnapshotState::capture(world, entities.into_iter(), world.resource::<UndoSnapshotStore>().latest());
+        let id = world.resource_mut::<UndoSnapshotStore>().insert(WorldSnapshot {
+            state,
+            filter: self.0,
+        });
+
+        let mut state = SystemState::<UndoScheduler<UndoSnapshotEvent>>::new(world);
+        state
+            .get_mut(world)
+            .register_with_redo(UndoSnapshotEvent(id), UndoSnapshotEvent(id));
+        state.apply(world);
+
+        if let Some(budget) = world.get_resource::<UndoSnapshotMemoryBudget>().copied() {
+            world.resource_scope(|world, mut store: Mut<UndoSnapshotStore>| {
+                store.fit(budget, &mut world.resource_mut::<UndoRegisteredArea<UndoSnapshotEvent>>());
+            });
+        }
+    }
+}
+
+
+fn matching<F: ReadOnlyWorldQuery + 'static>(world: &mut World) -> Vec<Entity> {
+    world
+        .query_filtered::<Entity, F>()
+        .iter(world)
+        .collect()
+}
+
+
+fn undo_snapshot_event_system(
+    world: &mut World,
+    mut er: Local<ManualEventReader<UndoSnapshotEvent>>,
+) {
+    let events = er
+        .iter(world.resource::<Events<UndoSnapshotEvent>>())
+        .copied()
+        .collect::<Vec<_>>();
+
+    world.resource_scope(|world, mut store: Mut<UndoSnapshotStore>| {
+        for UndoSnapshotEvent(id) in events {
+            store.restore(id, world);
+        }
+        store.prune(world.resource::<UndoRegisteredArea<UndoSnapshotEvent>>());
+    });
+}
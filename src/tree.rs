@@ -0,0 +1,147 @@
+use bevy::prelude::{Event, Resource};
+
+use crate::counter::UndoCounter;
+
+/// A sequence of undone steps that was split off from the current history.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct UndoBranch {
+    /// The id passed to [`UndoRequester::switch_branch`](crate::request::UndoRequester::switch_branch).
+    pub id: usize,
+
+    /// The last step shared with the history this branch was split off from.
+    pub fork: usize,
+
+    /// The number of steps the branch holds after [`fork`](UndoBranch::fork).
+    pub len: usize,
+
+    /// The branch this one was split off from, or `None` if it was split off from the current history.
+    ///
+    /// Only branches whose parent is `None` can be switched to.
+    pub parent: Option<usize>,
+}
+
+
+/// Keeps track of the steps that can be redone and, if enabled, of the branches of the undo tree.
+///
+/// In the default linear mode, registering an event after an undo discards the steps that were undone.
+/// After [`AppUndoEx::enable_undo_tree`](crate::extension::AppUndoEx::enable_undo_tree),
+/// they are kept as an [`UndoBranch`] instead.
+#[derive(Resource, Debug, Default, Clone, Eq, PartialEq)]
+pub struct UndoTree {
+    enabled: bool,
+    head: usize,
+    next_branch_id: usize,
+    branches: Vec<UndoBranch>,
+}
+
+
+impl UndoTree {
+    /// Returns true if the undo tree mode is enabled.
+    #[inline(always)]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+
+    /// Returns the latest step of the current history; steps above the undo counter up to this one can be redone.
+    #[inline(always)]
+    pub fn head(&self) -> usize {
+        self.head
+    }
+
+
+    /// Returns all branches of the undo tree.
+    #[inline(always)]
+    pub fn branches(&self) -> &[UndoBranch] {
+        &self.branches
+    }
+
+
+    /// Returns the branches which can currently be passed to [`UndoRequester::switch_branch`](crate::request::UndoRequester::switch_branch).
+    pub fn switchable_branches(&self) -> impl Iterator<Item=&UndoBranch> {
+        self.branches.iter().filter(|branch| branch.parent.is_none())
+    }
+
+
+    #[inline(always)]
+    pub(crate) fn enable(&mut self) {
+        self.enabled = true;
+    }
+
+
+    /// Called before `len` new steps are created on top of `counter`.
+    ///
+    /// Returns the event that tells each registered area what to do with the steps which can be redone.
+    pub(crate) fn advance(&mut self, counter: &UndoCounter, len: usize) -> Option<UndoBranchEvent> {
+        let fork = **counter;
+        let event = (fork < self.head).then(|| UndoBranchEvent {
+            fork,
+            stash: self.enabled.then(|| self.stash(fork)),
+            restore: None,
+        });
+        self.head = fork + len;
+        event
+    }
+
+
+    #[inline]
+    pub(crate) fn redo(&mut self, counter: &mut UndoCounter, step: usize) {
+        if **counter + 1 == step && step <= self.head {
+            counter.increment();
+        }
+    }
+
+
+    /// Returns the fork of the branch if it can be switched to at the step `counter`.
+    pub(crate) fn switchable_fork(&self, counter: usize, id: usize) -> Option<usize> {
+        self
+            .switchable_branches()
+            .find(|branch| branch.id == id && counter <= branch.fork)
+            .map(|branch| branch.fork)
+    }
+
+
+    pub(crate) fn switch(&mut self, counter: &UndoCounter, id: usize) -> Option<UndoBranchEvent> {
+        self.switchable_fork(**counter, id)?;
+        let index = self.branches.iter().position(|branch| branch.id == id)?;
+        let UndoBranch { fork, len, .. } = self.branches.remove(index);
+        let stash = (fork < self.head).then(|| self.stash(fork));
+        for branch in self.branches.iter_mut().filter(|branch| branch.parent == Some(id)) {
+            branch.parent = None;
+        }
+        self.head = fork + len;
+
+        Some(UndoBranchEvent {
+            fork,
+            stash,
+            restore: Some(id),
+        })
+    }
+
+
+    fn stash(&mut self, fork: usize) -> usize {
+        let id = self.next_branch_id;
+        self.next_branch_id += 1;
+
+        for branch in self.branches.iter_mut().filter(|branch| branch.parent.is_none() && fork < branch.fork) {
+            branch.parent = Some(id);
+        }
+        self.branches.push(UndoBranch {
+            id,
+            fork,
+            len: self.head - fork,
+            parent: None,
+        });
+        id
+    }
+}
+
+
+/// Tells each registered area to move the steps after `fork` which can be redone into the branch `stash`,
+/// or discard them if it is `None`, and then to restore the steps of the branch `restore`.
+#[derive(Event, Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) struct UndoBranchEvent {
+    pub fork: usize,
+    pub stash: Option<usize>,
+    pub restore: Option<usize>,
+}
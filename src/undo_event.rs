@@ -1,18 +1,72 @@
+use bevy::core::FrameCount;
 use bevy::ecs::system::SystemParam;
-use bevy::prelude::{Event, EventWriter, ResMut};
+use bevy::prelude::{Event, EventWriter, Res, ResMut};
+use bevy::time::Time;
 
-use crate::counter::UndoCounter;
+use crate::counter::{UndoCounter, UndoEntryIds};
+use crate::history::{UndoAuthor, UndoEntryMeta};
 use crate::reserve::{RequestCommitReservationsEvent, RequestCommitReservationsFromSchedulerEvent, ReserveCounter, UndoReservedArea, UndoReserveEvent};
+use crate::tree::{UndoBranchEvent, UndoTree};
+use crate::UndoRegisteredArea;
 
 #[cfg(feature = "callback_event")]
 pub mod callback;
 
-#[derive(Event, Clone)]
-pub(crate) struct UndoEvent<E: Event + Clone> {
+pub(crate) struct UndoEvent<E: Event> {
     pub inner: E,
+    pub redo: Option<RedoEvent<E>>,
     pub no: usize,
+
+    /// The id issued at registration, shared by the entries registered together.
+    pub id: usize,
+
+    /// True if the step is undone and redone together with the previous one, as for the reservations after the first.
+    pub chained: bool,
+    pub meta: UndoEntryMeta,
+}
+
+
+/// The redo-event of an entry.
+///
+/// Since a redoable entry has to send its events each time it is undone or redone,
+/// it keeps the function that clones them.
+pub(crate) struct RedoEvent<E> {
+    pub inner: E,
+    pub clone: fn(&E) -> E,
+}
+
+
+impl<E: Event + Clone> RedoEvent<E> {
+    #[inline(always)]
+    pub fn new(inner: E) -> Self {
+        Self {
+            inner,
+            clone: E::clone,
+        }
+    }
+}
+
+/// Identifies an entry registered via [`UndoScheduler::register`].
+///
+/// Pass it to [`UndoRequester::undo_entry`](crate::request::UndoRequester::undo_entry) to undo just that entry.
+/// The entry is looked up by an id that is never reused, so once the entry has left the history,
+/// for example because its step was undone and another entry was registered in its place, the handle refers to nothing.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct UndoHandle {
+    pub(crate) no: usize,
+    pub(crate) id: usize,
+}
+
+
+impl UndoHandle {
+    /// Returns the step of the undo counter the entry was registered at.
+    #[inline(always)]
+    pub const fn no(&self) -> usize {
+        self.no
+    }
 }
 
+
 #[derive(SystemParam)]
 pub struct UndoReserveCommitter<'w> {
     ew: EventWriter<'w, RequestCommitReservationsEvent>,
@@ -28,27 +82,74 @@ impl<'w> UndoReserveCommitter<'w> {
 
 
 #[derive(SystemParam)]
-pub struct UndoScheduler<'w, E: Event + Clone> {
+pub struct UndoScheduler<'w, E: Event> {
     counter: ResMut<'w, UndoCounter>,
     reserve: ResMut<'w, UndoReservedArea<E>>,
     reserve_counter: ResMut<'w, ReserveCounter>,
-    undo_writer: EventWriter<'w, UndoEvent<E>>,
+    registered: ResMut<'w, UndoRegisteredArea<E>>,
+    ids: ResMut<'w, UndoEntryIds>,
     reserve_writer: EventWriter<'w, RequestCommitReservationsFromSchedulerEvent>,
+    branch_writer: EventWriter<'w, UndoBranchEvent>,
+    tree: ResMut<'w, UndoTree>,
+    time: Option<Res<'w, Time>>,
+    frame: Option<Res<'w, FrameCount>>,
+    author: Option<Res<'w, UndoAuthor>>,
 }
 
 
-impl<'w, E: Event + Clone> UndoScheduler<'w, E> {
+impl<'w, E: Event> UndoScheduler<'w, E> {
     /// Register the undo-event　in the registered area.
     ///
     /// Events can registered multiple, and when [`UndoRequester::undo`](crate::request::UndoRequester) is called,
     /// last registered will sent
     #[inline(always)]
-    pub fn register(&mut self, event: E) {
-        self.counter.increment();
-        self.undo_writer.send(UndoEvent {
-            inner: event,
-            no: **self.counter,
-        });
+    pub fn register(&mut self, event: E) -> UndoHandle {
+        self.push(event, None)
+    }
+
+
+    /// Register the undo-event in the registered area together with the event to send when the step is redone.
+    ///
+    /// After this entry is undone, [`UndoRequester::redo`](crate::request::UndoRequester::redo) will send `redo`.
+    #[inline(always)]
+    pub fn register_with_redo(&mut self, undo: E, redo: E) -> UndoHandle
+        where E: Clone
+    {
+        self.push(undo, Some(RedoEvent::new(redo)))
+    }
+
+
+    /// Register the pairs of undo-event and redo-event in the registered area as one step.
+    ///
+    /// Returns `None` without creating a step if `entries` is empty.
+    pub(crate) fn register_group_with_redo(&mut self, entries: impl IntoIterator<Item=(E, E)>) -> Option<UndoHandle>
+        where E: Clone
+    {
+        let entries = entries
+            .into_iter()
+            .map(|(undo, redo)| (undo, Some(RedoEvent::new(redo))))
+            .collect::<Vec<_>>();
+        (!entries.is_empty()).then(|| self.push_group(entries))
+    }
+
+
+    /// Modifies the undo-event of the most recent entry, instead of registering a new one.
+    ///
+    /// This is useful when the same property is tweaked again right after an action.
+    /// Returns false, without calling `f`, if the most recent step does not hold an entry of type `E`.
+    #[inline]
+    pub fn amend_last(&mut self, f: impl FnOnce(&mut E)) -> bool {
+        self.last_mut().map(|undo| f(&mut undo.inner)).is_some()
+    }
+
+
+    /// Modifies the redo-event of the most recent entry.
+    ///
+    /// Returns false, without calling `f`, if the most recent step does not hold an entry of type `E`
+    /// or the entry was registered without a redo-event.
+    #[inline]
+    pub fn amend_last_redo(&mut self, f: impl FnOnce(&mut E)) -> bool {
+        self.last_mut().and_then(|undo| undo.redo.as_mut()).map(|redo| f(&mut redo.inner)).is_some()
     }
 
 
@@ -60,30 +161,90 @@ impl<'w, E: Event + Clone> UndoScheduler<'w, E> {
     /// This method is useful when want to sent  multiple undo-event with single call [`UndoRequest::undo`](crate::request::UndoRequester) .
     #[inline]
     pub fn reserve(&mut self, event: E) {
+        self.reserve_entry(event, None);
+    }
+
+
+    /// Place the undo-event in the reserved area together with the event to send when the step is redone.
+    #[inline]
+    pub fn reserve_with_redo(&mut self, undo: E, redo: E)
+        where E: Clone
+    {
+        self.reserve_entry(undo, Some(RedoEvent::new(redo)));
+    }
+
+
+    /// Moves all events placed on the reserved area by [`reserve`](UndoScheduler::reserve) to the registered area.
+    #[inline]
+    pub fn register_all_reserved(&mut self) {
+        self.reserve_writer.send(RequestCommitReservationsFromSchedulerEvent);
+    }
+
+
+    #[inline]
+    fn push(&mut self, event: E, redo: Option<RedoEvent<E>>) -> UndoHandle {
+        self.push_group(vec![(event, redo)])
+    }
+
+
+    fn push_group(&mut self, entries: Vec<(E, Option<RedoEvent<E>>)>) -> UndoHandle {
+        if let Some(branch) = self.tree.advance(&self.counter, 1) {
+            self.branch_writer.send(branch);
+        }
+        self.counter.increment();
+        let meta = self.meta();
+        let id = self.ids.issue();
+        for (event, redo) in entries {
+            self.registered.push(UndoEvent {
+                inner: event,
+                redo,
+                no: **self.counter,
+                id,
+                chained: false,
+                meta: meta.clone(),
+            });
+        }
+        UndoHandle { no: **self.counter, id }
+    }
+
+
+    #[inline]
+    fn last_mut(&mut self) -> Option<&mut UndoEvent<E>> {
+        self.registered.last_mut(**self.counter)
+    }
+
+
+    fn reserve_entry(&mut self, event: E, redo: Option<RedoEvent<E>>) {
         self.reserve_counter.increment();
+        let meta = self.meta();
         self.reserve.push(UndoReserveEvent {
             inner: event,
+            redo,
             reserve_no: **self.reserve_counter,
+            id: self.ids.issue(),
+            meta,
         });
     }
 
 
-    /// Moves all events placed on the reserved area by [`reserve`](UndoScheduler::reserve) to the registered area.
-    #[inline]
-    pub fn register_all_reserved(&mut self) {
-        self.reserve_writer.send(RequestCommitReservationsFromSchedulerEvent);
+    fn meta(&self) -> UndoEntryMeta {
+        UndoEntryMeta {
+            elapsed: self.time.as_ref().map(|time| time.elapsed()).unwrap_or_default(),
+            frame: self.frame.as_ref().map(|frame| frame.0).unwrap_or_default(),
+            author: self.author.as_deref().cloned(),
+        }
     }
 }
 
 
-impl<'w, E: Event + Clone + Default> UndoScheduler<'w, E> {
+impl<'w, E: Event + Default> UndoScheduler<'w, E> {
     /// Register the undo-event　in the registered area with default value.
     ///
     /// Events can registered multiple, and when [`UndoRequester::undo`](crate::request::UndoRequester) is called,
     /// last registered will sent
     #[inline(always)]
-    pub fn register_default(&mut self) {
-        self.register(E::default());
+    pub fn register_default(&mut self) -> UndoHandle {
+        self.register(E::default())
     }
 
 
@@ -1,7 +1,8 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 use bevy::app::{App, Plugin, Update};
-use bevy::prelude::{Commands, Event, EventReader};
+use bevy::ecs::event::{Events, ManualEventReader};
+use bevy::prelude::{Commands, Event, EventReader, Local, World};
 
 use crate::extension::AppUndoEx;
 
@@ -13,7 +14,13 @@ impl Plugin for UndoCallbackEventPlugin {
     fn build(&self, app: &mut App) {
         app
             .add_undo_event::<UndoCallbackEvent>()
-            .add_systems(Update, undo_callback_event_system);
+            .add_undo_event::<UndoWorldCallbackEvent>()
+            .add_undo_event::<UndoOnceCallbackEvent>()
+            .add_systems(Update, (
+                undo_callback_event_system,
+                undo_world_callback_event_system,
+                undo_once_callback_event_system,
+            ));
     }
 }
 
@@ -31,6 +38,48 @@ impl UndoCallbackEvent {
 }
 
 
+/// The callback is called with exclusive access to the [`World`],
+/// so unlike [`UndoCallbackEvent`] it can read components and resources while undoing.
+#[derive(Event, Clone)]
+#[repr(transparent)]
+pub struct UndoWorldCallbackEvent(Arc<dyn Fn(&mut World) + Send + Sync + 'static>);
+
+
+impl UndoWorldCallbackEvent {
+    #[inline(always)]
+    pub fn new(f: impl Fn(&mut World) + Send + Sync + 'static) -> Self {
+        Self(Arc::new(f))
+    }
+}
+
+
+/// The callback is called at most once, so it can move owned data, such as a despawned component,
+/// out of its captures.
+///
+/// Captured values only need to be [`Send`]; neither [`Clone`] nor [`Sync`] is required.
+/// The callback is taken out of the event when it is undone, so the entry can not be redone.
+#[derive(Event)]
+#[repr(transparent)]
+pub struct UndoOnceCallbackEvent(Mutex<Option<OnceCallback>>);
+
+
+type OnceCallback = Box<dyn FnOnce(&mut Commands) + Send + 'static>;
+
+
+impl UndoOnceCallbackEvent {
+    #[inline(always)]
+    pub fn new(f: impl FnOnce(&mut Commands) + Send + 'static) -> Self {
+        Self(Mutex::new(Some(Box::new(f))))
+    }
+
+
+    #[inline]
+    pub(crate) fn take(&self) -> Option<OnceCallback> {
+        self.0.lock().ok()?.take()
+    }
+}
+
+
 #[inline]
 pub(crate) fn undo_callback_event_system(
     mut commands: Commands,
@@ -42,3 +91,27 @@ pub(crate) fn undo_callback_event_system(
 }
 
 
+pub(crate) fn undo_world_callback_event_system(
+    world: &mut World,
+    mut er: Local<ManualEventReader<UndoWorldCallbackEvent>>,
+) {
+    let callbacks = er
+        .iter(world.resource::<Events<UndoWorldCallbackEvent>>())
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for e in callbacks {
+        e.0(world);
+    }
+}
+
+
+#[inline]
+pub(crate) fn undo_once_callback_event_system(
+    mut commands: Commands,
+    mut er: EventReader<UndoOnceCallbackEvent>,
+) {
+    for f in er.iter().filter_map(UndoOnceCallbackEvent::take) {
+        f(&mut commands);
+    }
+}
@@ -0,0 +1,126 @@
+use std::any::type_name;
+
+use bevy::ecs::entity::Entities;
+use bevy::prelude::{Entity, Event, Resource};
+
+use crate::UndoRegisteredArea;
+
+/// Returns the entities an undo event acts on, so that entries whose entities no longer exist can be detected.
+///
+/// Enable the check with [`AppUndoEx::validate_undo_targets`](crate::extension::AppUndoEx::validate_undo_targets).
+pub trait UndoTargets {
+    fn targets(&self) -> Vec<Entity>;
+}
+
+
+/// What happens to an entry whose targets no longer exist when it is undone or redone.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum UndoInvalidEntryPolicy {
+    /// The event is not sent, but the entry stays in the history,
+    /// so it can still be undone or redone after its targets are restored.
+    #[default]
+    Skip,
+
+    /// The entry is removed from the history.
+    Drop,
+}
+
+
+/// Sent for each entry that was skipped or dropped because its targets no longer exist.
+#[derive(Event, Debug, Clone, Eq, PartialEq)]
+pub struct UndoInvalidEntryEvent {
+    /// The step the entry belongs to.
+    pub no: usize,
+
+    /// The type name of the undo event.
+    pub event: &'static str,
+
+    /// The targets that no longer exist.
+    pub missing: Vec<Entity>,
+
+    pub policy: UndoInvalidEntryPolicy,
+}
+
+
+#[derive(Resource)]
+pub(crate) struct UndoValidation<E> {
+    targets: fn(&E) -> Vec<Entity>,
+    policy: UndoInvalidEntryPolicy,
+}
+
+
+impl<E: Event + UndoTargets> UndoValidation<E> {
+    #[inline(always)]
+    pub fn new(policy: UndoInvalidEntryPolicy) -> Self {
+        Self {
+            targets: E::targets,
+            policy,
+        }
+    }
+}
+
+
+impl<E: Event> UndoValidation<E> {
+    /// Removes the invalid entries of the step `no` from the history, if the policy is [`UndoInvalidEntryPolicy::Drop`].
+    pub fn drop_invalid(
+        &self,
+        registered: &mut UndoRegisteredArea<E>,
+        no: usize,
+        redo: bool,
+        entities: &Entities,
+        reports: &mut Vec<UndoInvalidEntryEvent>,
+    ) {
+        if self.policy == UndoInvalidEntryPolicy::Drop {
+            registered.retain_step(no, redo, |event| self.check(no, event, entities, reports));
+        }
+    }
+
+
+    /// Moves the invalid entries of the step `no` that have no redo-event to the redo area, if the policy is [`UndoInvalidEntryPolicy::Skip`],
+    /// so that undoing the step does not move them out of the history.
+    pub fn keep_skipped(
+        &self,
+        registered: &mut UndoRegisteredArea<E>,
+        no: usize,
+        entities: &Entities,
+        reports: &mut Vec<UndoInvalidEntryEvent>,
+    ) {
+        if self.policy == UndoInvalidEntryPolicy::Skip {
+            registered.keep_for_redo(no, |event| !self.check(no, event, entities, reports));
+        }
+    }
+
+
+    /// Returns the events whose targets all exist, and adds the reports of the others to `reports`.
+    pub fn validate(
+        &self,
+        no: usize,
+        events: Vec<E>,
+        entities: &Entities,
+        reports: &mut Vec<UndoInvalidEntryEvent>,
+    ) -> Vec<E> {
+        events
+            .into_iter()
+            .filter(|event| self.check(no, event, entities, reports))
+            .collect()
+    }
+
+
+    fn check(&self, no: usize, event: &E, entities: &Entities, reports: &mut Vec<UndoInvalidEntryEvent>) -> bool {
+        let missing = (self.targets)(event)
+            .into_iter()
+            .filter(|entity| !entities.contains(*entity))
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return true;
+        }
+
+        reports.push(UndoInvalidEntryEvent {
+            no,
+            event: type_name::<E>(),
+            missing,
+            policy: self.policy,
+        });
+        false
+    }
+}
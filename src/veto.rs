@@ -0,0 +1,54 @@
+use bevy::prelude::{Event, Resource, World};
+
+use crate::request::{UndoDirection, UndoStep};
+
+/// The decision of a veto hook about an undo or redo request.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum UndoVerdict {
+    Allow,
+
+    /// The request is discarded.
+    Reject(String),
+
+    /// The request is kept and checked again in the next frame.
+    Postpone(String),
+}
+
+
+/// Sent when an undo or redo request is rejected or postponed by a veto hook, or rejected because the step has no redo-events.
+#[derive(Event, Debug, Clone, Eq, PartialEq)]
+pub struct UndoVetoedEvent {
+    /// The step that was requested to be undone or redone.
+    pub no: usize,
+    pub direction: UndoDirection,
+
+    /// The verdict of the hook, either [`UndoVerdict::Reject`] or [`UndoVerdict::Postpone`].
+    pub verdict: UndoVerdict,
+}
+
+
+type VetoHook = Box<dyn Fn(&World, UndoDirection, usize) -> UndoVerdict + Send + Sync>;
+
+
+/// The veto hooks added via [`AppUndoEx::add_undo_veto`](crate::extension::AppUndoEx::add_undo_veto).
+#[derive(Resource, Default)]
+pub(crate) struct UndoVetoes(Vec<VetoHook>);
+
+
+impl UndoVetoes {
+    #[inline]
+    pub fn push(&mut self, hook: impl Fn(&World, UndoDirection, usize) -> UndoVerdict + Send + Sync + 'static) {
+        self.0.push(Box::new(hook));
+    }
+
+
+    /// Returns the first verdict other than [`UndoVerdict::Allow`].
+    pub fn verdict(&self, world: &World, UndoStep { no, direction }: UndoStep) -> UndoVerdict {
+        self
+            .0
+            .iter()
+            .map(|hook| hook(world, direction, no))
+            .find(|verdict| *verdict != UndoVerdict::Allow)
+            .unwrap_or(UndoVerdict::Allow)
+    }
+}